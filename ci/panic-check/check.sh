@@ -0,0 +1,25 @@
+#!/bin/sh
+# Checks that a firmware image built with the `panic-free` feature contains none of the
+# driver's panic messages. usb-device itself still panics, so the image as a whole isn't
+# panic-free, but every explicit panic of the driver leaves its message in the image.
+#
+# Usage: check.sh <firmware image>
+set -eu
+
+image="$1"
+src="$(dirname "$0")/../../src"
+tmp="$(mktemp -d)"
+trap 'rm -rf "$tmp"' EXIT
+
+strings -a "$image" > "$tmp/strings"
+# Messages of `fatal!` and `assert!`, up to the first format argument
+grep -ohE '(fatal|assert)!\([^"]*"[^"{]+' "$src"/*.rs | sed -E 's/^[^"]*"//' | sort -u > "$tmp/messages"
+
+status=0
+while IFS= read -r message; do
+    if grep -qF -- "$message" "$tmp/strings"; then
+        echo "panic message found: $message"
+        status=1
+    fi
+done < "$tmp/messages"
+exit $status
@@ -0,0 +1,51 @@
+//! Minimal firmware image that links the driver with the `panic-free` feature, see `check.sh`
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use synopsys_usb_otg::{UsbBus, UsbPeripheral};
+use usb_device::endpoint::{EndpointIn, EndpointOut};
+use usb_device::prelude::*;
+
+struct Peripheral;
+
+unsafe impl UsbPeripheral for Peripheral {
+    const REGISTERS: *const () = 0x5000_0000 as *const ();
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 6;
+
+    fn enable() {}
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        168_000_000
+    }
+}
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let usb_bus = UsbBus::new(Peripheral, unsafe { &mut *core::ptr::addr_of_mut!(EP_MEMORY) });
+    let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+    let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd)).build();
+
+    // Echo bulk packets, so that all data paths of the driver are linked in
+    let mut buf = [0u8; 64];
+    loop {
+        if usb_dev.poll(&mut []) {
+            if let Ok(count) = ep_out.read(&mut buf) {
+                ep_in.write(&buf[..count]).ok();
+            }
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Keeps the panic messages in the image
+    core::hint::black_box(info);
+    loop {}
+}
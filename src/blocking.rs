@@ -0,0 +1,35 @@
+//! Blocking helpers for simple firmware
+
+use embedded_hal::blocking::delay::DelayMs;
+use usb_device::bus::UsbBus;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+
+/// Polls the device until the host has configured it, or until `timeout_ms` milliseconds have
+/// elapsed.
+///
+/// The device is polled once per millisecond, with `delay` used to wait in between, so the
+/// classes get their requests handled while waiting. Returns `true` if the device is configured,
+/// `false` if the timeout expired first.
+///
+/// This is meant for bring-up code and tests. Real firmware should poll the device from the USB
+/// interrupt or its main loop instead of blocking, since the device has to keep being polled
+/// after it's configured, and stops responding to the host while the firmware does something
+/// else.
+pub fn block_until_configured<B: UsbBus>(
+    device: &mut UsbDevice<'_, B>,
+    classes: &mut [&mut dyn UsbClass<B>],
+    delay: &mut impl DelayMs<u32>,
+    timeout_ms: u32,
+) -> bool {
+    for _ in 0..timeout_ms {
+        device.poll(classes);
+        if device.state() == UsbDeviceState::Configured {
+            return true;
+        }
+        delay.delay_ms(1);
+    }
+
+    device.poll(classes);
+    device.state() == UsbDeviceState::Configured
+}
@@ -1,3 +1,4 @@
+use core::cell::Cell;
 use core::marker::PhantomData;
 use embedded_hal::blocking::delay::DelayMs;
 use usb_device::{Result, UsbDirection, UsbError};
@@ -8,15 +9,131 @@ use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, otg_pw
 
 use crate::target::UsbRegisters;
 use crate::target::interrupt::{self, Mutex, CriticalSection};
-use crate::endpoint::{EndpointIn, EndpointOut};
-use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBufferState};
+use crate::endpoint::{spin_until, Endpoint, EndpointIn, EndpointOut};
+use crate::endpoint_memory::{EndpointMemoryAllocator, EndpointBufferState, RX_FIFO_HEADROOM_WORDS};
 use crate::{UsbPeripheral, PhyType};
+#[cfg(feature = "hs")]
+use crate::PhyClockSource;
+#[cfg(feature = "throughput")]
+use crate::throughput::{ThroughputCounters, ThroughputStats};
+#[cfg(feature = "trace")]
+use core::cell::RefCell;
+#[cfg(feature = "trace")]
+use crate::trace::{TraceBuffer, TraceEvent, TraceRecord};
+
+/// Lowest AHB frequency supported at full speed, from the GUSBCFG.TRDT table
+const FS_MIN_AHB_FREQUENCY_HZ: u32 = 14_200_000;
+
+/// Lowest AHB frequency supported at high speed
+const HS_MIN_AHB_FREQUENCY_HZ: u32 = 30_000_000;
+
+/// Write-1-to-clear bits of GINTSTS.
+///
+/// The remaining bits are either read-only or reflect the state of other registers and are
+/// cleared by servicing their source.
+const GINTSTS_W1C_MASK: u32 =
+    (1 << 1) |  // MMIS
+    (1 << 3) |  // SOF
+    (1 << 10) | // ESUSP
+    (1 << 11) | // USBSUSP
+    (1 << 12) | // USBRST
+    (1 << 13) | // ENUMDNE
+    (1 << 14) | // ISOODRP
+    (1 << 15) | // EOPF
+    (1 << 20) | // IISOIXFR
+    (1 << 21) | // IPXFR/INCOMPISOOUT
+    (1 << 22) | // DATAFSUSP (HS cores)
+    (1 << 28) | // CIDSCHG
+    (1 << 29) | // DISCINT
+    (1 << 30) | // SRQINT
+    (1 << 31);  // WKUPINT
+
+/// Maximum number of RX FIFO entries handled by one `poll` call.
+///
+/// Each OUT endpoint buffer holds a single packet, so the RX FIFO is usually drained much
+/// earlier. The limit only bounds the time spent in `poll` under a steady stream of packets.
+const RX_ENTRIES_PER_POLL: usize = 8;
+
+/// Maximum number of RX FIFO entries and IN endpoint rounds handled by one `poll` call in drain
+/// mode, see `UsbBus::set_drain_fully`.
+const DRAIN_LIMIT: usize = 64;
+
+/// Time the device is kept disconnected after a warm reset, see `UsbBus::finish_warm_reset`
+/// and `UsbBus::reenumerate`.
+///
+/// Long enough for the host to notice the disconnect.
+const WARM_RESET_DISCONNECT_MS: u32 = 10;
+
+/// Function called on bus reset, see `UsbBus::set_reset_callback`.
+pub type ResetCallback = fn();
+
+/// Function called with each SETUP packet on EP0, see `UsbBus::set_setup_filter`.
+pub type SetupFilter = fn(&[u8; 8]) -> SetupAction;
 
 /// USB peripheral driver for STM32 microcontrollers.
 pub struct UsbBus<USB> {
     peripheral: USB,
     regs: Mutex<UsbRegisters>,
     allocator: EndpointAllocator<USB>,
+    device_state: Mutex<Cell<DeviceState>>,
+    tx_fifo_map: Mutex<Cell<[u8; 9]>>,
+    tx_fifo_size_overrides: Mutex<Cell<[u16; 9]>>,
+    rx_fifo_size_override: Mutex<Cell<u16>>,
+    fifo_profile: Mutex<Cell<Option<FifoProfile>>>,
+    ep0_in_zlp: Mutex<Cell<bool>>,
+    status_stage_complete: Mutex<Cell<bool>>,
+    /// The current control transfer has an IN data stage (bmRequestType bit 7)
+    control_data_in: Mutex<Cell<bool>>,
+    control_transfer_active: Mutex<Cell<bool>>,
+    control_transfer_aborted: Mutex<Cell<bool>>,
+    #[cfg(feature = "throughput")]
+    throughput: Mutex<Cell<ThroughputCounters>>,
+    #[cfg(feature = "trace")]
+    trace: Mutex<RefCell<TraceBuffer>>,
+    power_source: Mutex<Cell<PowerSource>>,
+    remote_wakeup_enabled: Mutex<Cell<bool>>,
+    suspended: Mutex<Cell<bool>>,
+    resume_frame: Mutex<Cell<Option<u16>>>,
+    clock_gating_pending: Mutex<Cell<bool>>,
+    speed: Mutex<Cell<Option<UsbSpeed>>>,
+    hs_negotiation_failed: Mutex<Cell<bool>>,
+    ahb_frequency_hz: Mutex<Cell<Option<u32>>>,
+    high_speed_enabled: Mutex<Cell<bool>>,
+    on_reset: Mutex<Cell<Option<ResetCallback>>>,
+    setup_filter: Mutex<Cell<Option<SetupFilter>>>,
+    filtered_stage: Mutex<Cell<FilteredStage>>,
+    idle: Mutex<Cell<IdleTracker>>,
+    host_silence: Mutex<Cell<HostSilenceMonitor>>,
+    enumeration: Mutex<Cell<EnumerationTimer>>,
+    enumeration_watchdog: Mutex<Cell<EnumerationWatchdog>>,
+    disconnect_pending: Mutex<Cell<bool>>,
+    reset_count: Mutex<Cell<u32>>,
+    reset_rate: Mutex<Cell<ResetRateTracker>>,
+    warm_reset_pending: Mutex<Cell<bool>>,
+    configure_on_enumeration: Mutex<Cell<bool>>,
+    configured_at_enumeration: Mutex<Cell<bool>>,
+    poll_error: Mutex<Cell<bool>>,
+    out_token_disabled_count: Mutex<Cell<u32>>,
+    out_naks: Mutex<Cell<OutNakMonitor>>,
+    in_underruns: Mutex<Cell<[u32; 9]>>,
+    setup_overflow: Mutex<Cell<bool>>,
+    last_grxsts: Mutex<Cell<u32>>,
+    drain_fully: Mutex<Cell<bool>>,
+    events: Mutex<Cell<EventQueue>>,
+    poll_handled: Mutex<Cell<bool>>,
+    vbus_detection: Mutex<Cell<VbusDetection>>,
+    vbus_valid: Mutex<Cell<bool>>,
+    in_endpoint_interrupts: Mutex<Cell<InEndpointInterrupts>>,
+    in_idle_zlp: Mutex<Cell<u16>>,
+    in_idle_zlp_pending: Mutex<Cell<u16>>,
+    tx_refill_half_empty: Mutex<Cell<u16>>,
+    tx_refill_waiting: Mutex<Cell<u16>>,
+    tx_refill_ready: Mutex<Cell<u16>>,
+    out_endpoint_interrupts: Mutex<Cell<OutEndpointInterrupts>>,
+    #[cfg(feature = "dedicated-interrupts")]
+    dedicated_ep1_in: Mutex<Cell<bool>>,
+    #[cfg(feature = "dedicated-interrupts")]
+    dedicated_in_complete: Mutex<Cell<u16>>,
 }
 
 impl<USB: UsbPeripheral> UsbBus<USB> {
@@ -26,34 +143,196 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
             peripheral,
             regs: Mutex::new(UsbRegisters::new::<USB>()),
             allocator: EndpointAllocator::new(ep_memory),
+            device_state: Mutex::new(Cell::new(DeviceState::Default)),
+            tx_fifo_map: Mutex::new(Cell::new([0, 1, 2, 3, 4, 5, 6, 7, 8])),
+            tx_fifo_size_overrides: Mutex::new(Cell::new([0; 9])),
+            rx_fifo_size_override: Mutex::new(Cell::new(0)),
+            fifo_profile: Mutex::new(Cell::new(None)),
+            ep0_in_zlp: Mutex::new(Cell::new(false)),
+            status_stage_complete: Mutex::new(Cell::new(false)),
+            control_data_in: Mutex::new(Cell::new(false)),
+            control_transfer_active: Mutex::new(Cell::new(false)),
+            control_transfer_aborted: Mutex::new(Cell::new(false)),
+            #[cfg(feature = "throughput")]
+            throughput: Mutex::new(Cell::new(ThroughputCounters::default())),
+            #[cfg(feature = "trace")]
+            trace: Mutex::new(RefCell::new(TraceBuffer::new())),
+            power_source: Mutex::new(Cell::new(PowerSource::SelfPowered)),
+            remote_wakeup_enabled: Mutex::new(Cell::new(false)),
+            suspended: Mutex::new(Cell::new(false)),
+            resume_frame: Mutex::new(Cell::new(None)),
+            clock_gating_pending: Mutex::new(Cell::new(false)),
+            speed: Mutex::new(Cell::new(None)),
+            hs_negotiation_failed: Mutex::new(Cell::new(false)),
+            ahb_frequency_hz: Mutex::new(Cell::new(None)),
+            high_speed_enabled: Mutex::new(Cell::new(true)),
+            on_reset: Mutex::new(Cell::new(None)),
+            setup_filter: Mutex::new(Cell::new(None)),
+            filtered_stage: Mutex::new(Cell::new(FilteredStage::None)),
+            idle: Mutex::new(Cell::new(IdleTracker::default())),
+            host_silence: Mutex::new(Cell::new(HostSilenceMonitor::default())),
+            enumeration: Mutex::new(Cell::new(EnumerationTimer::default())),
+            enumeration_watchdog: Mutex::new(Cell::new(EnumerationWatchdog::default())),
+            disconnect_pending: Mutex::new(Cell::new(false)),
+            reset_count: Mutex::new(Cell::new(0)),
+            reset_rate: Mutex::new(Cell::new(ResetRateTracker::default())),
+            warm_reset_pending: Mutex::new(Cell::new(false)),
+            configure_on_enumeration: Mutex::new(Cell::new(false)),
+            configured_at_enumeration: Mutex::new(Cell::new(false)),
+            poll_error: Mutex::new(Cell::new(false)),
+            out_token_disabled_count: Mutex::new(Cell::new(0)),
+            out_naks: Mutex::new(Cell::new(OutNakMonitor::default())),
+            in_underruns: Mutex::new(Cell::new([0; 9])),
+            setup_overflow: Mutex::new(Cell::new(false)),
+            last_grxsts: Mutex::new(Cell::new(0)),
+            drain_fully: Mutex::new(Cell::new(false)),
+            events: Mutex::new(Cell::new(EventQueue::default())),
+            poll_handled: Mutex::new(Cell::new(false)),
+            vbus_detection: Mutex::new(Cell::new(VbusDetection::Disabled)),
+            vbus_valid: Mutex::new(Cell::new(true)),
+            in_endpoint_interrupts: Mutex::new(Cell::new(InEndpointInterrupts::default())),
+            in_idle_zlp: Mutex::new(Cell::new(0)),
+            in_idle_zlp_pending: Mutex::new(Cell::new(0)),
+            tx_refill_half_empty: Mutex::new(Cell::new(0)),
+            tx_refill_waiting: Mutex::new(Cell::new(0)),
+            tx_refill_ready: Mutex::new(Cell::new(0)),
+            out_endpoint_interrupts: Mutex::new(Cell::new(OutEndpointInterrupts::default())),
+            #[cfg(feature = "dedicated-interrupts")]
+            dedicated_ep1_in: Mutex::new(Cell::new(false)),
+            #[cfg(feature = "dedicated-interrupts")]
+            dedicated_in_complete: Mutex::new(Cell::new(0)),
         };
 
         UsbBusAllocator::new(bus)
     }
 
+    /// Constructs a new USB peripheral driver with a statically-sized endpoint memory.
+    ///
+    /// Unlike `new`, the size of the endpoint memory is checked at compile time: the build fails
+    /// if it exceeds the FIFO size of the peripheral.
+    pub fn new_sized<const WORDS: usize>(peripheral: USB, ep_memory: &'static mut [u32; WORDS]) -> UsbBusAllocator<Self> {
+        let () = EndpointMemoryFits::<USB, WORDS>::OK;
+
+        Self::new(peripheral, ep_memory)
+    }
+
     pub fn free(self) -> USB {
         self.peripheral
     }
 
-    fn configure_all(&self, cs: &CriticalSection) {
-        let regs = self.regs.borrow(cs);
+    /// Hands the peripheral over to other code, e.g. a bootloader, with the connection intact.
+    ///
+    /// The core stays enabled and connected, and all registers, including the device address,
+    /// the FIFO layout and the endpoint configuration, are left as they are, so the new owner can
+    /// continue servicing the device without the host enumerating it again. Pending interrupts
+    /// aren't cleared either: disable the USB interrupt in the NVIC before handing over.
+    pub fn into_raw(self) -> USB {
+        self.peripheral
+    }
 
-        // Rx FIFO
+    /// Returns the RX FIFO size in words, see `set_fifo_profile` and `set_rx_fifo_size_words`
+    fn rx_fifo_size_words(&self) -> u16 {
+        let (profile, size_override) = interrupt::free(|cs| {
+            (self.fifo_profile.borrow(cs).get(), self.rx_fifo_size_override.borrow(cs).get())
+        });
+        match (profile, size_override) {
+            (Some(profile), _) => profile.rx_fifo_size_words,
+            (None, 0) => self.auto_rx_fifo_size_words(),
+            (None, size_words) => size_words,
+        }
+    }
+
+    /// Returns the minimum RX FIFO size in words given by the reference manuals
+    fn min_rx_fifo_size_words(&self) -> u16 {
+        let out_endpoints = self.allocator.endpoints_out.iter().flatten().count();
+        let largest_packet = self.allocator.endpoints_out.iter().flatten()
+            .map(|ep| ep.max_packet_size() as usize)
+            .max()
+            .unwrap_or(0);
+        // SETUP packets of the control endpoint, the largest packet with its status word, and
+        // the transfer complete status of every OUT endpoint
+        (5 + 8 + largest_packet.div_ceil(4) + 1 + 2 * out_endpoints + 1) as u16
+    }
+
+    /// Returns the RX FIFO size in words required by the allocated OUT endpoints
+    fn auto_rx_fifo_size_words(&self) -> u16 {
         // This calculation doesn't correspond to one in a Reference Manual.
         // In fact, the required number of words is higher than indicated in RM.
         // The following numbers are pessimistic and were figured out empirically.
-        let rx_fifo_size = if USB::HIGH_SPEED {
+        if USB::HIGH_SPEED {
             self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
         } else {
             // F429 requires 35+ words for the (EP0[8] + EP2[64]) setup
             // F446 requires 39+ words for the same setup
             self.allocator.memory_allocator.total_rx_buffer_size_words() + 30
-        };
+        }
+    }
+
+    /// Returns TX FIFO sizes in words, indexed by FIFO number
+    fn tx_fifo_sizes_words(&self, cs: &CriticalSection) -> [u16; 9] {
+        if let Some(profile) = self.fifo_profile.borrow(cs).get() {
+            return profile.tx_fifo_sizes_words;
+        }
+
+        let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+        let overrides = self.tx_fifo_size_overrides.borrow(cs).get();
+        self.compute_tx_fifo_sizes_words(&tx_fifo_map, &overrides)
+    }
+
+    /// Computes TX FIFO sizes in words for the allocated IN endpoints, see `tx_fifo_sizes_for`
+    fn compute_tx_fifo_sizes_words(&self, tx_fifo_map: &[u8; 9], overrides: &[u16; 9]) -> [u16; 9] {
+        let mut endpoint_sizes = [0; 9];
+        for (i, size) in endpoint_sizes.iter_mut().enumerate() {
+            *size = self.allocator.memory_allocator.tx_fifo_size_words(i);
+        }
+        tx_fifo_sizes_for(&endpoint_sizes, tx_fifo_map, overrides)
+    }
+
+    /// Returns the number of FIFO RAM words taken by a FIFO layout
+    fn fifo_layout_words(rx_fifo_size: u16, tx_fifo_sizes: &[u16; 9]) -> usize {
+        let tx_total: usize = tx_fifo_sizes.iter().take(USB::ENDPOINT_COUNT).map(|&size| size as usize).sum();
+        rx_fifo_size as usize + tx_total
+    }
+
+    /// Checks that a FIFO layout fits into the FIFO RAM
+    fn fifo_layout_fits(rx_fifo_size: u16, tx_fifo_sizes: &[u16; 9]) -> bool {
+        Self::fifo_layout_words(rx_fifo_size, tx_fifo_sizes) <= USB::FIFO_DEPTH_WORDS
+    }
+
+    /// Checks that a FIFO layout fits into the FIFO RAM, reporting it to the peripheral if not
+    fn check_fifo_layout(&self, rx_fifo_size: u16, tx_fifo_sizes: &[u16; 9]) -> bool {
+        if Self::fifo_layout_fits(rx_fifo_size, tx_fifo_sizes) {
+            return true;
+        }
+
+        self.peripheral.fifo_overflow(FifoOverflow {
+            endpoint: None,
+            requested_words: Self::fifo_layout_words(rx_fifo_size, tx_fifo_sizes),
+            available_words: USB::FIFO_DEPTH_WORDS,
+        });
+        false
+    }
+
+    /// Programs the FIFO layout
+    ///
+    /// The RX FIFO always starts at address 0 and occupies words `0..rx_fifo_size`, GRXFSIZ only
+    /// holds its depth. It is programmed first, as required by the reference manuals. The TX
+    /// FIFOs follow back to back: TX FIFO 0 (DIEPTXF0 on FS cores, GNPTXFSIZ at the same offset
+    /// on HS cores) starts at word `rx_fifo_size`, and every other TX FIFO starts where the
+    /// previous one ends, so no two FIFOs overlap.
+    fn write_fifo_layout(regs: &UsbRegisters, rx_fifo_size: u16, tx_fifo_sizes: &[u16; 9]) {
+        if !Self::fifo_layout_fits(rx_fifo_size, tx_fifo_sizes) {
+            fatal!("FIFO layout doesn't fit into the FIFO RAM");
+            // Leave the FIFOs unconfigured, the endpoints won't work
+            return;
+        }
+
+        // Rx FIFO
         write_reg!(otg_global, regs.global(), GRXFSIZ, rx_fifo_size as u32);
         let mut fifo_top = rx_fifo_size;
 
         // Tx FIFO #0
-        let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(0);
+        let fifo_size = tx_fifo_sizes[0];
 
         #[cfg(feature = "fs")]
         write_reg!(otg_global, regs.global(), DIEPTXF0,
@@ -68,10 +347,9 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
 
         fifo_top += fifo_size;
 
-        // Tx FIFOs
-        for i in 1..USB::ENDPOINT_COUNT {
-            let fifo_size = self.allocator.memory_allocator.tx_fifo_size_words(i);
-
+        // Tx FIFOs. FIFOs without an IN endpoint, e.g. all of them on a device with only EP0,
+        // get a depth of 0 and take no FIFO RAM.
+        for (i, &fifo_size) in tx_fifo_sizes.iter().enumerate().take(USB::ENDPOINT_COUNT).skip(1) {
             let dieptxfx = regs.dieptxfx(i);
             write_reg!(otg_global_dieptxfx, dieptxfx, DIEPTXFx,
                 INEPTXFD: fifo_size as u32,
@@ -80,35 +358,64 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
 
             fifo_top += fifo_size;
         }
+    }
+
+    fn configure_all(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
 
-        assert!(fifo_top as usize <= USB::FIFO_DEPTH_WORDS);
+        let rx_fifo_size = self.rx_fifo_size_words();
+        let tx_fifo_sizes = self.tx_fifo_sizes_words(cs);
+        self.check_fifo_layout(rx_fifo_size, &tx_fifo_sizes);
+        Self::write_fifo_layout(regs, rx_fifo_size, &tx_fifo_sizes);
 
         // Flush Rx & Tx FIFOs
         modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
-        while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) != (0, 0) {}
+        // This runs from `poll` and `reset`, so a stuck flush is reported like other poll timeouts
+        if !spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) == (0, 0)) {
+            self.set_poll_error(cs);
+            return;
+        }
+        #[cfg(feature = "trace")]
+        self.trace_fifo_flushes(cs);
+
+        // A reset returns all interfaces to their default alternate setting
+        for ep in self.allocator.endpoints_in.iter().flatten() {
+            ep.set_active_max_packet_size(cs, ep.max_packet_size());
+        }
+        for ep in self.allocator.endpoints_out.iter().flatten() {
+            ep.set_active_max_packet_size(cs, ep.max_packet_size());
+        }
+
+        self.configure_endpoints(cs);
+    }
+
+    fn configure_endpoints(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
 
         for ep in &self.allocator.endpoints_in {
             if let Some(ep) = ep {
-                // enabling EP TX interrupt
-                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (0x0001 << ep.address().index()));
+                // enabling EP TX interrupt, unless it has a dedicated interrupt line
+                if !self.has_dedicated_interrupt(cs, ep.address().index()) {
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (0x0001 << ep.address().index()));
+                }
 
-                ep.configure(cs);
+                ep.configure(cs, tx_fifo_map[ep.address().index()]);
             }
         }
 
         for ep in &self.allocator.endpoints_out {
             if let Some(ep) = ep {
-                if ep.address().index() == 0 {
-                    // enabling RX interrupt from EP0
-                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | 0x00010000);
-                }
+                // enabling OUT endpoint interrupts
+                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (0x00010000 << ep.address().index()));
 
                 ep.configure(cs);
             }
         }
     }
 
-    fn deconfigure_all(&self, cs: &CriticalSection) {
+    /// Deconfigures all endpoints, returns `false` if an OUT endpoint didn't disable in time
+    fn deconfigure_all(&self, cs: &CriticalSection) -> bool {
         let regs = self.regs.borrow(cs);
 
         // disable interrupts
@@ -120,11 +427,295 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
             }
         }
 
+        let mut disabled = true;
         for ep in &self.allocator.endpoints_out {
             if let Some(ep) = ep {
-                ep.deconfigure(cs);
+                disabled &= ep.deconfigure(cs);
+            }
+        }
+        disabled
+    }
+
+    /// Waits for the core during initialization, see `UsbPeripheral::init_wait_backoff`
+    fn wait_for_core(&self, mut done: impl FnMut() -> bool) -> bool {
+        let mut round = 0;
+        loop {
+            if spin_until(&mut done) {
+                return true;
+            }
+            round += 1;
+            if !self.peripheral.init_wait_backoff(round) {
+                return false;
+            }
+        }
+    }
+
+    /// Initializes the core, returns `false` if it doesn't respond
+    fn init_core(&self, cs: &CriticalSection) -> bool {
+        let regs = self.regs.borrow(cs);
+
+        let core_id = read_reg!(otg_global, regs.global(), CID);
+
+        // Enumeration is timed from the first bus reset after initialization
+        self.enumeration.borrow(cs).set(EnumerationTimer::default());
+        let watchdog = self.enumeration_watchdog.borrow(cs);
+        watchdog.set(EnumerationWatchdog { limit: watchdog.get().limit, ..Default::default() });
+
+        // Wait for AHB ready
+        if !self.wait_for_core(|| read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) != 0) {
+            return false;
+        }
+
+        // Configure OTG as device
+        #[cfg(feature = "fs")]
+        modify_reg!(otg_global, regs.global(), GUSBCFG,
+            SRPCAP: 0, // SRP capability is not enabled
+            TOCAL: self.peripheral.timeout_calibration() as u32,
+            FDMOD: 1 // Force device mode
+        );
+        #[cfg(feature = "hs")]
+        modify_reg!(otg_global, regs.global(), GUSBCFG,
+            SRPCAP: 0, // SRP capability is not enabled
+            TOCAL: self.peripheral.timeout_calibration() as u32,
+            FDMOD: 1 // Force device mode
+        );
+
+        // Configure USB PHY
+        #[cfg(feature = "hs")]
+        match self.peripheral.phy_type() {
+            PhyType::InternalFullSpeed => {
+                // Select FS Embedded PHY
+                modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
+            },
+            PhyType::InternalHighSpeed => {
+                // Turn off PHY
+                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+                // Init The UTMI Interface
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    TSDPS: 0,
+                    ULPIFSLS: 0,
+                    PHYSEL: 0 // ULPI or UTMI
+                );
+
+                // Select VBUS source
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    ULPIEVBUSD: 0,
+                    ULPIEVBUSI: 0
+                );
+
+                // Select UTMI Interace
+                //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
+                modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
+
+                // This is a secret bit from ST that is not mentioned anywhere except
+                // the driver code shipped with STM32CubeIDE.
+                //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
+
+                Self::select_phy_clock(regs, self.peripheral.phy_clock_source());
+
+                self.peripheral.setup_internal_hs_phy();
+            }
+            PhyType::ExternalHighSpeed => {
+                // Turn off embedded PHY
+                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
+
+                // Init The ULPI Interface
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    TSDPS: 0,
+                    ULPIFSLS: 0,
+                    PHYSEL: 0 // ULPI or UTMI
+                );
+
+                // Select VBUS source
+                modify_reg!(otg_global, regs.global(), GUSBCFG,
+                    ULPIEVBUSD: 0,
+                    ULPIEVBUSI: 0
+                );
+
+                Self::select_phy_clock(regs, self.peripheral.phy_clock_source());
             }
         }
+
+        // Perform core soft-reset
+        if !self.wait_for_core(|| read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) != 0) {
+            return false;
+        }
+        modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
+        if !self.wait_for_core(|| read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 0) {
+            return false;
+        }
+
+        // Activate the USB Transceiver. It is powered up after the PHY selection and the core
+        // soft-reset, and before VBUS sensing is configured and the pull-up is enabled.
+        self.power_up_transceiver(regs);
+
+        // Configuring Vbus sense and SOF output
+        let detection = self.vbus_detection.borrow(cs).get();
+        let vbus_valid = self.vbus_valid.borrow(cs).get();
+        if Self::write_vbus_detection(regs, core_id, detection, vbus_valid).is_err() {
+            Self::write_vbus_detection(regs, core_id, VbusDetection::Disabled, vbus_valid).ok();
+        }
+
+        // Enable PHY clock
+        write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
+
+        // Soft disconnect device
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+        // Setup USB speed and frame interval
+        modify_reg!(otg_device, regs.device(), DCFG,
+            PFIVL: 0b00,
+            DSPD: self.device_speed(cs)
+        );
+        #[cfg(feature = "xcvrdly")]
+        modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
+
+        // unmask EP interrupts
+        self.update_endpoint_interrupt_masks(cs);
+
+        // unmask core interrupts
+        write_reg!(otg_global, regs.global(), GINTMSK,
+            USBRST: 1, ENUMDNEM: 1,
+            USBSUSPM: 1, WUIM: 1,
+            IEPINT: 1, OEPINT: 1, RXFLVLM: 1,
+            IISOIXFRM: 1
+        );
+        //modify_reg!(otg_global, regs.global(), GINTMSK, IPXFRM_IISOOXFRM: 1);
+        modify_reg!(otg_global, regs.global(), GINTMSK, |r| r | (1 << 21));
+
+        // clear pending endpoint interrupts left over from a previous session, a core
+        // soft-reset doesn't clear them
+        for index in 0..USB::ENDPOINT_COUNT {
+            use crate::ral::{endpoint_in, endpoint_out};
+
+            write_reg!(endpoint_in, regs.endpoint_in(index), DIEPINT, 0xffff);
+            write_reg!(endpoint_out, regs.endpoint_out(index), DOEPINT, 0xffff);
+        }
+
+        // clear pending interrupts
+        write_reg!(otg_global, regs.global(), GINTSTS, GINTSTS_W1C_MASK);
+
+        // unmask global interrupt
+        modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
+
+        // connect(true), unless the host has to see a disconnect first
+        if !self.warm_reset_pending.borrow(cs).get() {
+            self.soft_connect(regs);
+        }
+
+        true
+    }
+
+    /// Returns the DCFG.DSPD value for the PHY and the runtime speed setting
+    fn device_speed(&self, cs: &CriticalSection) -> u32 {
+        let high_speed = self.high_speed_enabled.borrow(cs).get();
+        match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
+            (false, _) => 0b11,
+            (true, PhyType::InternalFullSpeed) => 0b11,
+            // Full speed using the high-speed PHY
+            (true, _) if !high_speed => 0b01,
+            (true, PhyType::InternalHighSpeed) => 0b00,
+            (true, PhyType::ExternalHighSpeed) => 0b00,
+        }
+    }
+
+    fn power_up_transceiver(&self, regs: &UsbRegisters) {
+        if self.peripheral.phy_type() == PhyType::InternalFullSpeed {
+            modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+        }
+    }
+
+    /// Enables the pull-up.
+    ///
+    /// The pull-up is part of the transceiver, so connecting with the transceiver powered down
+    /// leaves the device invisible to the host. It is powered up here if it isn't already.
+    fn soft_connect(&self, regs: &UsbRegisters) {
+        if self.peripheral.phy_type() == PhyType::InternalFullSpeed &&
+           read_reg!(otg_global, regs.global(), GCCFG, PWRDWN) == 0 {
+            self.power_up_transceiver(regs);
+        }
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
+    }
+
+    /// Returns the current device state as seen by the driver.
+    ///
+    /// The driver tracks bus resets and address assignment by itself. The configured state is
+    /// not visible to the driver and has to be reported with `set_configured`.
+    pub fn device_state(&self) -> DeviceState {
+        interrupt::free(|cs| self.device_state.borrow(cs).get())
+    }
+
+    /// Reports whether the device has been configured by the host.
+    ///
+    /// This should be called when the `usb-device` stack enters or leaves the configured state.
+    /// Has no effect before an address has been assigned.
+    pub fn set_configured(&self, configured: bool) {
+        interrupt::free(|cs| {
+            let state = self.device_state.borrow(cs);
+            match (state.get(), configured) {
+                (DeviceState::Address, true) => state.set(DeviceState::Configured),
+                (DeviceState::Configured, false) => state.set(DeviceState::Address),
+                _ => {}
+            }
+        });
+    }
+
+    /// Disconnects the device from the bus by removing the pull-up.
+    pub fn disconnect(&self) {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+        });
+    }
+
+    /// Connects the device to the bus by enabling the pull-up.
+    pub fn connect(&self) {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            self.warm_reset_pending.borrow(cs).set(false);
+            self.soft_connect(regs);
+        });
+    }
+
+    /// Simulates unplugging the cable.
+    ///
+    /// The device is disconnected from the bus and the driver state is torn down as it would be
+    /// on a bus reset, including the reset callback. The next `poll` returns `PollResult::Reset`
+    /// so that the `usb-device` stack and the class drivers go through their reset handling.
+    /// Call `connect` to simulate plugging the cable back in.
+    ///
+    /// This is intended for testing the disconnect handling of class drivers, not for normal
+    /// operation.
+    pub fn simulate_disconnect(&self) {
+        interrupt::free(|cs| self.disconnect_and_tear_down(cs));
+    }
+
+    /// Forces the host to enumerate the device again, e.g. after its descriptors have changed.
+    ///
+    /// The device is disconnected and the driver state is torn down as by `simulate_disconnect`,
+    /// so the next `poll` returns `PollResult::Reset` and the `usb-device` stack and the class
+    /// drivers start over. The device is kept disconnected for 10 ms, long enough for the host
+    /// to notice, and then connected again, after which the host reads the descriptors anew.
+    ///
+    /// The endpoints allocated through `UsbBusAllocator` stay as they are. Descriptors that use
+    /// a different set of endpoints need a new allocator, built from the peripheral returned by
+    /// `free`.
+    pub fn reenumerate(&self, delay: &mut impl DelayMs<u32>) {
+        interrupt::free(|cs| self.disconnect_and_tear_down(cs));
+        delay.delay_ms(WARM_RESET_DISCONNECT_MS);
+        self.connect();
+    }
+
+    /// Disconnects the device and resets the driver state as a bus reset would
+    fn disconnect_and_tear_down(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+
+        self.tear_down(cs);
+        self.device_state.borrow(cs).set(DeviceState::Default);
+        self.disconnect_pending.borrow(cs).set(true);
     }
 
     pub fn force_reset(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
@@ -132,102 +723,3739 @@ impl<USB: UsbPeripheral> UsbBus<USB> {
             let regs = self.regs.borrow(cs);
             write_reg!(otg_device, regs.device(), DCTL, SDIS: 1); // Soft disconnect
             delay.delay_ms(3);
-            write_reg!(otg_device, regs.device(), DCTL, SDIS: 0); // Soft connect
+            self.soft_connect(regs); // Soft connect
             delay.delay_ms(3);
         });
         Ok(())
     }
 
-    #[cfg(feature = "hs")]
-    /// Reads from a ULPI register in an external ULPI PHY.
+    /// Resets the whole core with a core soft-reset (GRSTCTL.CSRST) and re-initializes it.
     ///
-    /// Interrupts are disabled for the duration of the function call.
+    /// This is the last resort for recovering from a state that endpoint-level recovery, like
+    /// `reset_all_endpoints` or `force_reset`, can't fix. The device is disconnected, the driver
+    /// state is torn down as on a bus reset, and the core is reset and re-initialized as it is by
+    /// `enable`, after which it connects to the bus again. The next `poll` returns
+    /// `PollResult::Reset`, and the endpoints are reconfigured when the host resets the device.
     ///
-    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`.
+    /// Returns `UsbError::InvalidState` if the core doesn't become idle or doesn't complete the
+    /// reset in time. The device is left disconnected in that case.
+    pub fn core_soft_reset(&self) -> Result<()> {
+        interrupt::free(|cs| {
+            self.disconnect_and_tear_down(cs);
+
+            if !self.init_core(cs) {
+                return Err(UsbError::InvalidState);
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns `true` if `enable` detected a warm reset and left the device disconnected.
     ///
-    /// # Example
+    /// See `finish_warm_reset`.
+    pub fn warm_reset_pending(&self) -> bool {
+        interrupt::free(|cs| self.warm_reset_pending.borrow(cs).get())
+    }
+
+    /// Connects the device after a warm reset, once the host has had time to notice the
+    /// disconnect.
     ///
-    /// ```
-    /// # use synopsys_usb_otg::{UsbPeripheral, UsbBus};
-    /// fn read_usb_vid_pid<USB: UsbPeripheral>(bus: &UsbBus<USB>) -> (u16, u16) {
-    ///     let mut vid: u16 = bus.ulpi_read(0x00) as u16;
-    ///     vid |= (bus.ulpi_read(0x01) as u16) << 8;
-    ///     let mut pid: u16 = bus.ulpi_read(0x02) as u16;
-    ///     pid |= (bus.ulpi_read(0x03) as u16) << 8;
-    ///     (vid, pid)
-    /// }
-    /// ```
-    pub fn ulpi_read(&self, addr: u8) -> core::result::Result<u8, UlpiError> {
-        if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
-            panic!("ulpi_read is only supported with external ULPI PHYs");
+    /// When the MCU is reset while the device is enumerated, e.g. by a watchdog, the host may
+    /// not notice, and doesn't enumerate the device again if it simply reconnects. The reset
+    /// also clears the core, so `enable` relies on `UsbPeripheral::warm_reset` to detect this
+    /// case, and leaves the device disconnected. This function
+    /// keeps it disconnected for a few more milliseconds and then connects it, after which the
+    /// host enumerates it from scratch. Call it right after building the `usb-device` stack.
+    ///
+    /// Does nothing if no warm reset has been detected.
+    pub fn finish_warm_reset(&self, delay: &mut impl DelayMs<u32>) {
+        if !self.warm_reset_pending() {
+            return;
         }
 
-        interrupt::free(|cs| {
-            let regs = self.regs.borrow(cs);
+        delay.delay_ms(WARM_RESET_DISCONNECT_MS);
+        self.connect();
+    }
 
-            // Begin ULPI register read transaction
-            modify_reg!(otg_global, regs.global(), PHYCR,
-                NEW: 1,
-                RW: 0,
-                ADDR: addr as u32
-            );
+    /// Takes a snapshot of the endpoint configuration and FIFO layout.
+    ///
+    /// The snapshot can be kept in retained memory, directly or as bytes (see
+    /// `ConfigSnapshot::to_bytes`), and passed to `restore_config` after the USB core has lost
+    /// its state, e.g. after the MCU wakes up from standby. Endpoints record the packet size of
+    /// their current alternate setting.
+    pub fn save_config(&self) -> ConfigSnapshot {
+        interrupt::free(|cs| {
+            let mut config = ConfigSnapshot {
+                endpoints_in: [EndpointSnapshot::default(); 9],
+                endpoints_out: [EndpointSnapshot::default(); 9],
+                rx_fifo_size_words: self.rx_fifo_size_words(),
+                tx_fifo_size_words: self.tx_fifo_sizes_words(cs),
+                tx_fifo_numbers: self.tx_fifo_map.borrow(cs).get(),
+            };
 
-            // ULPI transactions should take less than 1us. 1000 iterations should be enough even for fast MCUs.
-            let mut timeout = 1000;
-            while timeout > 0 {
-                // Wait for transaction to complete
-                if read_reg!(otg_global, regs.global(), PHYCR, DONE) != 0 {
-                    // Read transaction data
-                    return Ok((read_reg!(otg_global, regs.global(), PHYCR, DATA) & 0xFF) as u8);
+            for (i, ep) in self.allocator.endpoints_in.iter().enumerate() {
+                if let Some(ep) = ep {
+                    config.endpoints_in[i] = EndpointSnapshot::new(cs, ep);
                 }
-                timeout -= 1;
             }
-            Err(UlpiError::Timeout)
+            for (i, ep) in self.allocator.endpoints_out.iter().enumerate() {
+                if let Some(ep) = ep {
+                    config.endpoints_out[i] = EndpointSnapshot::new(cs, ep);
+                }
+            }
+
+            config
         })
     }
 
-    #[cfg(feature = "hs")]
-    /// Writes to a ULPI register in an external ULPI PHY.
+    /// Re-applies a configuration snapshot taken with `save_config`.
     ///
-    /// Interrupts are disabled for the duration of the function call.
+    /// The USB core is re-initialized and the TX FIFO mapping, FIFO layout and endpoint packet
+    /// sizes are programmed from the snapshot, without going through endpoint allocation again.
+    /// The FIFO layout replaces the FIFO profile (see `set_fifo_profile`), so it stays in effect
+    /// after bus resets. The device is connected to the bus afterwards and the host is expected
+    /// to re-enumerate it.
     ///
-    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`.
-    pub fn ulpi_write(&self, addr: u8, data: u8) -> core::result::Result<(), UlpiError> {
-        if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
-            panic!("ulpi_write is only supported with external ULPI PHYs");
+    /// The snapshot is validated before anything is programmed. Returns `UsbError::InvalidState`
+    /// if its endpoints don't match the endpoints allocated on this bus or if the core doesn't
+    /// respond, `UsbError::InvalidEndpoint` for an invalid TX FIFO mapping,
+    /// `UsbError::BufferOverflow` if a FIFO is too small for its endpoints and
+    /// `UsbError::EndpointMemoryOverflow` if the FIFO layout doesn't fit into the FIFO RAM.
+    pub fn restore_config(&self, config: &ConfigSnapshot) -> Result<()> {
+        self.check_config_snapshot(config)?;
+        if !self.check_fifo_layout(config.rx_fifo_size_words, &config.tx_fifo_size_words) {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        USB::enable();
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if !self.init_core(cs) {
+                return Err(UsbError::InvalidState);
+            }
+
+            self.tx_fifo_map.borrow(cs).set(config.tx_fifo_numbers);
+            self.fifo_profile.borrow(cs).set(Some(FifoProfile {
+                rx_fifo_size_words: config.rx_fifo_size_words,
+                tx_fifo_sizes_words: config.tx_fifo_size_words,
+            }));
+            for (ep, snapshot) in self.allocator.endpoints_in.iter().zip(&config.endpoints_in) {
+                if let Some(ep) = ep {
+                    ep.set_active_max_packet_size(cs, snapshot.max_packet_size);
+                }
+            }
+            for (ep, snapshot) in self.allocator.endpoints_out.iter().zip(&config.endpoints_out) {
+                if let Some(ep) = ep {
+                    ep.set_active_max_packet_size(cs, snapshot.max_packet_size);
+                }
+            }
+
+            Self::write_fifo_layout(regs, config.rx_fifo_size_words, &config.tx_fifo_size_words);
+            self.configure_endpoints(cs);
+            Ok(())
+        })
+    }
+
+    /// Checks a snapshot against the allocated endpoints, see `restore_config`
+    fn check_config_snapshot(&self, config: &ConfigSnapshot) -> Result<()> {
+        for (index, (ep, snapshot)) in self.allocator.endpoints_in.iter().zip(&config.endpoints_in).enumerate() {
+            if !snapshot.matches(ep.as_deref()) {
+                return Err(UsbError::InvalidState);
+            }
+            if ep.is_some() {
+                let fifo = config.tx_fifo_numbers[index];
+                if fifo as usize >= USB::ENDPOINT_COUNT || (index == 0) != (fifo == 0) {
+                    return Err(UsbError::InvalidEndpoint);
+                }
+                if (config.tx_fifo_size_words[fifo as usize] as usize) * 4 < snapshot.max_packet_size as usize {
+                    return Err(UsbError::BufferOverflow);
+                }
+            }
+        }
+
+        for (ep, snapshot) in self.allocator.endpoints_out.iter().zip(&config.endpoints_out) {
+            if !snapshot.matches(ep.as_deref()) {
+                return Err(UsbError::InvalidState);
+            }
         }
 
+        if config.rx_fifo_size_words < self.min_rx_fifo_size_words() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        Ok(())
+    }
+
+    /// Masks or unmasks the RX FIFO non-empty (RXFLVL) interrupt.
+    ///
+    /// While the interrupt is masked, `poll` doesn't pop received packets from the RX FIFO. OUT
+    /// data accumulates in the FIFO up to its depth, after which the core NAKs further OUT
+    /// transactions until the interrupt is unmasked again.
+    pub fn set_rxflvl_enabled(&self, enabled: bool) {
         interrupt::free(|cs| {
             let regs = self.regs.borrow(cs);
+            modify_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM: enabled as u32);
+        });
+    }
+
+    /// Selects whether `poll` keeps servicing endpoint events until none are left.
+    ///
+    /// By default a `poll` call pops at most a few RX FIFO entries and services the IN endpoints
+    /// once, so events that arrive in the meantime raise another interrupt. In drain mode, `poll`
+    /// keeps popping the RX FIFO and servicing IN endpoints while RXFLVL or IEPINT is set, up to
+    /// a fixed bound. This makes the ISR a bit longer, but reduces the interrupt rate under
+    /// sustained bulk traffic. Disabled by default.
+    pub fn set_drain_fully(&self, enabled: bool) {
+        interrupt::free(|cs| self.drain_fully.borrow(cs).set(enabled));
+    }
+
+    /// Selects how VBUS is detected.
+    ///
+    /// By default VBUS isn't sensed and the device always assumes that it's attached. The
+    /// available options depend on the core:
+    ///
+    /// * F429-like cores (core ID 0x1100 and 0x1200) can use the internal comparator with either
+    ///   threshold, but can't take an external signal.
+    /// * F446-like cores (core ID 0x2000 to 0x3100) have a single internal comparator with a
+    ///   fixed threshold, the threshold passed with `VbusDetection::Internal` is ignored. They
+    ///   can take the VBUS state from an external signal reported with `set_vbus_valid`.
+    /// * Other cores only support `VbusDetection::Disabled`.
+    ///
+    /// The setting takes effect immediately and is kept across `enable` calls. Returns
+    /// `UsbError::Unsupported` if the core doesn't support the selected detection.
+    pub fn set_vbus_detection(&self, detection: VbusDetection) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let core_id = read_reg!(otg_global, regs.global(), CID);
+            Self::write_vbus_detection(regs, core_id, detection, self.vbus_valid.borrow(cs).get())?;
+            self.vbus_detection.borrow(cs).set(detection);
+            Ok(())
+        })
+    }
+
+    /// Returns the selected VBUS detection.
+    pub fn vbus_detection(&self) -> VbusDetection {
+        interrupt::free(|cs| self.vbus_detection.borrow(cs).get())
+    }
+
+    /// Reports the VBUS state sensed by external circuitry.
+    ///
+    /// This should be called whenever the state changes, e.g. from a GPIO interrupt. It only
+    /// has an effect with `VbusDetection::External`, the last reported state is kept until then.
+    pub fn set_vbus_valid(&self, valid: bool) {
+        interrupt::free(|cs| {
+            self.vbus_valid.borrow(cs).set(valid);
+            if self.vbus_detection.borrow(cs).get() == VbusDetection::External {
+                let regs = self.regs.borrow(cs);
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOVAL: valid as u32);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| (r & !(1 << 7)) | ((valid as u32) << 7));
+            }
+        });
+    }
+
+    /// Selects the IN endpoint interrupt sources (DIEPMSK) that are reported via GINTSTS.IEPINT.
+    ///
+    /// The status bits in DIEPINTx are set regardless of the mask, the mask only controls whether
+    /// they trigger an interrupt. The setting is kept across `enable` calls.
+    pub fn set_in_endpoint_interrupts(&self, interrupts: InEndpointInterrupts) {
+        interrupt::free(|cs| {
+            self.in_endpoint_interrupts.borrow(cs).set(interrupts);
+            self.update_endpoint_interrupt_masks(cs);
+        });
+    }
+
+    /// Returns the IN endpoint interrupt sources that are currently unmasked.
+    pub fn in_endpoint_interrupts(&self) -> InEndpointInterrupts {
+        interrupt::free(|cs| self.in_endpoint_interrupts.borrow(cs).get())
+    }
+
+    /// Selects the OUT endpoint interrupt sources (DOEPMSK) that are reported via GINTSTS.OEPINT.
+    ///
+    /// The status bits in DOEPINTx are set regardless of the mask, the mask only controls whether
+    /// they trigger an interrupt. The setting is kept across `enable` calls.
+    pub fn set_out_endpoint_interrupts(&self, interrupts: OutEndpointInterrupts) {
+        interrupt::free(|cs| {
+            self.out_endpoint_interrupts.borrow(cs).set(interrupts);
+            self.update_endpoint_interrupt_masks(cs);
+        });
+    }
+
+    /// Returns the OUT endpoint interrupt sources that are currently unmasked.
+    pub fn out_endpoint_interrupts(&self) -> OutEndpointInterrupts {
+        interrupt::free(|cs| self.out_endpoint_interrupts.borrow(cs).get())
+    }
+
+    #[cfg(feature = "dedicated-interrupts")]
+    /// Moves the interrupts of IN endpoint 1 to the dedicated EP1 IN interrupt line.
+    ///
+    /// Some HS cores have dedicated interrupt lines for endpoint 1 (OTG_HS_EP1_IN and
+    /// OTG_HS_EP1_OUT on STM32), controlled by the DEACHINT registers. With this enabled, EP1 IN
+    /// events no longer go through the shared interrupt, and have to be serviced by calling
+    /// `poll_ep1_in` from the EP1 IN interrupt handler. The IN endpoint interrupt sources selected
+    /// with `set_in_endpoint_interrupts` apply to both lines. EP1 OUT stays on the shared line,
+    /// since OUT data is always received through the shared RX FIFO.
+    ///
+    /// The setting takes effect immediately and is kept across `enable` calls. Returns
+    /// `UsbError::Unsupported` if the core doesn't implement the DEACHINTMSK register.
+    pub fn set_dedicated_ep1_in_interrupt(&self, enabled: bool) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            if enabled {
+                // Cores without dedicated lines don't implement the register, it reads as zero
+                write_reg!(otg_device, regs.device(), DEACHINTMSK, IEP1INTM: 1);
+                if read_reg!(otg_device, regs.device(), DEACHINTMSK, IEP1INTM) == 0 {
+                    return Err(UsbError::Unsupported);
+                }
+            }
+
+            self.dedicated_ep1_in.borrow(cs).set(enabled);
+            if enabled {
+                self.update_endpoint_interrupt_masks(cs);
+                modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v & !(1 << 1));
+            } else {
+                write_reg!(otg_device, regs.device(), DEACHINTMSK, 0);
+                if self.allocator.endpoints_in[1].is_some() {
+                    modify_reg!(otg_device, regs.device(), DAINTMSK, |v| v | (1 << 1));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "dedicated-interrupts")]
+    /// Services IN endpoint 1, to be called from the dedicated EP1 IN interrupt handler.
+    ///
+    /// Only EP1 IN is looked at, so this doesn't race with `poll` for other endpoints. Completed
+    /// transfers are reported by the next `poll` call. Returns `true` if there is something to
+    /// report, in which case the application should poll the `usb-device` stack soon, e.g. by
+    /// pending the shared USB interrupt.
+    pub fn poll_ep1_in(&self) -> bool {
+        interrupt::free(|cs| {
+            let ep = match &self.allocator.endpoints_in[1] {
+                Some(ep) if self.dedicated_ep1_in.borrow(cs).get() => ep,
+                _ => return false,
+            };
+
+            let completed = self.service_in_endpoint(cs, ep);
+            let dedicated_in_complete = self.dedicated_in_complete.borrow(cs);
+            dedicated_in_complete.set(dedicated_in_complete.get() | completed);
+            completed != 0
+        })
+    }
+
+    #[cfg(feature = "hs")]
+    fn select_phy_clock(regs: &UsbRegisters, source: PhyClockSource) {
+        let phylpcs = match source {
+            PhyClockSource::Pll480MHz => 0,
+            PhyClockSource::External48MHz => 1,
+        };
+        modify_reg!(otg_global, regs.global(), GUSBCFG, PHYLPCS: phylpcs);
+    }
+
+    fn write_vbus_detection(regs: &UsbRegisters, core_id: u32, detection: VbusDetection, vbus_valid: bool) -> Result<()> {
+        match (core_id, detection) {
+            (0x0000_1200 | 0x0000_1100, VbusDetection::Disabled) => {
+                // F429-like chips have the GCCFG.NOVBUSSENS bit
+
+                //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
+            }
+            (0x0000_1200 | 0x0000_1100, VbusDetection::Internal(threshold)) => {
+                //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                let (vbusasen, vbusbsen) = match threshold {
+                    VbusThreshold::VbusValid => (1, 0),
+                    VbusThreshold::SessionValid => (0, 1),
+                };
+                modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: vbusasen, VBUSBSEN: vbusbsen, SOFOUTEN: 0);
+            }
+            (0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100, VbusDetection::Disabled) => {
+                // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
+
+                //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                // Force B-peripheral session
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r | (0b11 << 6));
+            }
+            (0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100, VbusDetection::Internal(_)) => {
+                // The comparator threshold is fixed on these chips
+
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 0, BVALOVAL: 0);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r & !(0b11 << 6));
+
+                //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 1);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
+            }
+            (0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100, VbusDetection::External) => {
+                //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
+                modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
+
+                // Override the B-peripheral session valid signal with the externally sensed state
+                //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: vbus_valid as u32);
+                modify_reg!(otg_global, regs.global(), GOTGCTL, |r| (r & !(0b11 << 6)) | (1 << 6) | ((vbus_valid as u32) << 7));
+            }
+            (_, VbusDetection::Disabled) => {}
+            _ => return Err(UsbError::Unsupported),
+        }
+
+        Ok(())
+    }
+
+    /// Selects what an IN endpoint does when the host polls it and no data has been written.
+    ///
+    /// By default the endpoint NAKs, as required by the USB specification for endpoints that
+    /// have nothing to send. With `InIdlePolicy::ZeroLengthPacket` the driver queues a
+    /// zero-length packet when it sees an IN token for the idle endpoint, so the host gets the
+    /// ZLP on its next attempt. These ZLPs are not reported to the class as completed writes.
+    ///
+    /// The core only reports IN tokens on an empty FIFO for bulk endpoints, so the policy can't
+    /// be changed for control, interrupt and isochronous endpoints, which always NAK. Returns
+    /// `UsbError::InvalidEndpoint` if the endpoint hasn't been allocated, and
+    /// `UsbError::Unsupported` if it isn't a bulk endpoint.
+    pub fn set_in_idle_policy(&self, ep_addr: EndpointAddress, policy: InIdlePolicy) -> Result<()> {
+        let ep = match self.allocator.endpoints_in.get(ep_addr.index()) {
+            Some(Some(ep)) if ep_addr.is_in() => ep,
+            _ => return Err(UsbError::InvalidEndpoint),
+        };
+        if ep.ep_type() != EndpointType::Bulk {
+            return Err(UsbError::Unsupported);
+        }
+
+        interrupt::free(|cs| {
+            let bit = 1 << ep_addr.index();
+            let idle_zlp = self.in_idle_zlp.borrow(cs);
+            match policy {
+                InIdlePolicy::Nak => idle_zlp.set(idle_zlp.get() & !bit),
+                InIdlePolicy::ZeroLengthPacket => idle_zlp.set(idle_zlp.get() | bit),
+            }
+            self.update_endpoint_interrupt_masks(cs);
+        });
+
+        Ok(())
+    }
+
+    /// Returns the idle policy of an IN endpoint.
+    pub fn in_idle_policy(&self, ep_addr: EndpointAddress) -> InIdlePolicy {
+        interrupt::free(|cs| {
+            if ep_addr.is_in() && self.in_idle_zlp.borrow(cs).get() & (1 << ep_addr.index()) != 0 {
+                InIdlePolicy::ZeroLengthPacket
+            } else {
+                InIdlePolicy::Nak
+            }
+        })
+    }
+
+    /// Selects when an IN endpoint that couldn't write because its TX FIFO was full is told to
+    /// retry.
+    ///
+    /// A `write` returns `UsbError::WouldBlock` when the TX FIFO doesn't have room for the packet,
+    /// which can happen when the FIFO is shared with other endpoints. The driver then unmasks the
+    /// FIFO empty interrupt of the endpoint (DIEPEMPMSK), and once the FIFO has drained to the
+    /// refill point, `poll` flags the endpoint in `take_tx_refills`, after which the application
+    /// should retry the write. With `TxRefillPolicy::Empty` (the default) this happens when the
+    /// FIFO is completely empty, which keeps the latency of the next packet predictable. With
+    /// `TxRefillPolicy::HalfEmpty` it happens as soon as the FIFO is half empty, so the FIFO is
+    /// refilled earlier. The interrupt itself fires at half empty (GAHBCFG.TXFELVL), so with
+    /// `TxRefillPolicy::Empty` it keeps firing while the rest of the FIFO drains.
+    ///
+    /// Returns `UsbError::InvalidEndpoint` if the endpoint hasn't been allocated.
+    pub fn set_tx_refill_policy(&self, ep_addr: EndpointAddress, policy: TxRefillPolicy) -> Result<()> {
+        match self.allocator.endpoints_in.get(ep_addr.index()) {
+            Some(Some(_)) if ep_addr.is_in() => {}
+            _ => return Err(UsbError::InvalidEndpoint),
+        }
+
+        interrupt::free(|cs| {
+            let bit = 1 << ep_addr.index();
+            let half_empty = self.tx_refill_half_empty.borrow(cs);
+            match policy {
+                TxRefillPolicy::Empty => half_empty.set(half_empty.get() & !bit),
+                TxRefillPolicy::HalfEmpty => half_empty.set(half_empty.get() | bit),
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Returns the refill policy of an IN endpoint.
+    pub fn tx_refill_policy(&self, ep_addr: EndpointAddress) -> TxRefillPolicy {
+        interrupt::free(|cs| {
+            if ep_addr.is_in() && self.tx_refill_half_empty.borrow(cs).get() & (1 << ep_addr.index()) != 0 {
+                TxRefillPolicy::HalfEmpty
+            } else {
+                TxRefillPolicy::Empty
+            }
+        })
+    }
+
+    /// Starts waiting for the TX FIFO of an endpoint whose write didn't fit
+    fn wait_for_tx_fifo(&self, cs: &CriticalSection, index: usize) {
+        use crate::ral::endpoint_in;
+
+        let regs = self.regs.borrow(cs);
+        // A busy endpoint reports its completion anyway
+        if read_reg!(endpoint_in, regs.endpoint_in(index), DIEPCTL, EPENA) != 0 {
+            return;
+        }
+
+        let bit = 1 << index;
+        let waiting = self.tx_refill_waiting.borrow(cs);
+        waiting.set(waiting.get() | bit);
+
+        // GAHBCFG.TXFELVL is left at its default, the interrupt fires when the FIFO is half empty
+        modify_reg!(otg_device, regs.device(), DIEPEMPMSK, |r| r | bit as u32);
+    }
+
+    /// Returns a bitmask of the IN endpoints whose TX FIFO has drained to the refill point since
+    /// the last call, bit N standing for endpoint N. See `set_tx_refill_policy`.
+    ///
+    /// These endpoints haven't completed a transfer, their last write failed with
+    /// `UsbError::WouldBlock` and should be retried now.
+    pub fn take_tx_refills(&self) -> u16 {
+        interrupt::free(|cs| self.tx_refill_ready.borrow(cs).replace(0))
+    }
+
+    /// Flags the IN endpoints whose TX FIFO has drained to the refill point
+    fn update_tx_refills(&self, cs: &CriticalSection) {
+        use crate::ral::endpoint_in;
+
+        let waiting = self.tx_refill_waiting.borrow(cs);
+        if waiting.get() == 0 {
+            return;
+        }
+
+        let regs = self.regs.borrow(cs);
+        let tx_fifo_sizes = self.tx_fifo_sizes_words(cs);
+        let half_empty = self.tx_refill_half_empty.borrow(cs).get();
+        let mut ready = 0;
+        for ep in self.allocator.endpoints_in.iter().flatten() {
+            let index = ep.address().index();
+            let bit = 1 << index;
+            if waiting.get() & bit == 0 {
+                continue;
+            }
+
+            let ep_regs = regs.endpoint_in(index);
+            let depth = tx_fifo_sizes[read_reg!(endpoint_in, ep_regs, DIEPCTL, TXFNUM) as usize];
+            let available = read_reg!(endpoint_in, ep_regs, DTXFSTS, INEPTFSAV) as u16;
+            let refill_point = if half_empty & bit != 0 { depth / 2 } else { depth };
+            if available >= refill_point {
+                ready |= bit;
+            }
+        }
+
+        if ready != 0 {
+            waiting.set(waiting.get() & !ready);
+            modify_reg!(otg_device, regs.device(), DIEPEMPMSK, |r| r & !(ready as u32));
+
+            let refills = self.tx_refill_ready.borrow(cs);
+            refills.set(refills.get() | ready);
+        }
+    }
+
+    /// Forgets a ZLP queued by the idle policy, after the endpoint has been aborted
+    fn clear_idle_zlp_pending(&self, cs: &CriticalSection, index: usize) {
+        let idle_zlp_pending = self.in_idle_zlp_pending.borrow(cs);
+        idle_zlp_pending.set(idle_zlp_pending.get() & !(1 << index));
+    }
+
+    fn update_endpoint_interrupt_masks(&self, cs: &CriticalSection) {
+        let mut in_ep = self.in_endpoint_interrupts.borrow(cs).get();
+        // The idle policy relies on the IN token received when TX FIFO empty interrupt
+        if self.in_idle_zlp.borrow(cs).get() != 0 {
+            in_ep.token_received_tx_fifo_empty = true;
+        }
+        let regs = self.regs.borrow(cs);
+        Self::write_endpoint_interrupt_masks(regs, in_ep, self.out_endpoint_interrupts.borrow(cs).get());
+
+        // The dedicated EP1 IN line uses its own copy of the IN endpoint mask
+        #[cfg(feature = "dedicated-interrupts")]
+        if self.dedicated_ep1_in.borrow(cs).get() {
+            write_reg!(otg_device, regs.device(), DIEPEACHMSK1, read_reg!(otg_device, regs.device(), DIEPMSK));
+            write_reg!(otg_device, regs.device(), DEACHINTMSK, IEP1INTM: 1);
+        }
+    }
+
+    /// Returns `true` if an IN endpoint is serviced through a dedicated interrupt line
+    fn has_dedicated_interrupt(&self, _cs: &CriticalSection, _index: usize) -> bool {
+        #[cfg(feature = "dedicated-interrupts")]
+        if _index == 1 && self.dedicated_ep1_in.borrow(_cs).get() {
+            return true;
+        }
+        false
+    }
+
+    fn write_endpoint_interrupt_masks(regs: &UsbRegisters, in_ep: InEndpointInterrupts, out_ep: OutEndpointInterrupts) {
+        write_reg!(otg_device, regs.device(), DIEPMSK,
+            XFRCM: in_ep.transfer_completed as u32,
+            EPDM: in_ep.endpoint_disabled as u32,
+            TOM: in_ep.timeout as u32,
+            ITTXFEMSK: in_ep.token_received_tx_fifo_empty as u32,
+            INEPNMM: in_ep.token_mismatch as u32,
+            INEPNEM: in_ep.nak_effective as u32
+        );
+        // FIFO underruns are always counted, see `in_underrun_count`
+        #[cfg(feature = "hs")]
+        modify_reg!(otg_device, regs.device(), DIEPMSK, TXFURM: 1);
+        write_reg!(otg_device, regs.device(), DOEPMSK,
+            XFRCM: out_ep.transfer_completed as u32,
+            EPDM: out_ep.endpoint_disabled as u32,
+            STUPM: out_ep.setup_done as u32,
+            OTEPDM: out_ep.token_received_endpoint_disabled as u32
+        );
+    }
+
+    /// Maps an IN endpoint to a TX FIFO.
+    ///
+    /// By default IN endpoint N uses TX FIFO N. Several IN endpoints may share one TX FIFO, in
+    /// which case the FIFO is sized for the largest of them. EP0 always uses TX FIFO 0, and
+    /// TX FIFO 0 can't be used by other endpoints.
+    ///
+    /// The new mapping takes effect on the next USB reset.
+    pub fn set_tx_fifo_number(&self, ep_addr: EndpointAddress, fifo: u8) -> Result<()> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if fifo as usize >= USB::ENDPOINT_COUNT || (ep_addr.index() == 0) != (fifo == 0) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|cs| {
+            let tx_fifo_map = self.tx_fifo_map.borrow(cs);
+            let mut map = tx_fifo_map.get();
+            map[ep_addr.index()] = fifo;
+            tx_fifo_map.set(map);
+        });
+
+        Ok(())
+    }
+
+    /// Returns `true` if the status stage of a control transfer has completed since the last call.
+    ///
+    /// The status stage is considered complete when the host has acknowledged the zero-length IN
+    /// packet on EP0 that ends a transfer without data stage or with an OUT data stage, or when
+    /// a zero-length OUT packet ends a transfer with an IN data stage. A zero-length packet that
+    /// terminates the data stage itself doesn't count. This can be used to defer actions that
+    /// must only happen after the control transfer is over.
+    pub fn take_status_stage_complete(&self) -> bool {
+        interrupt::free(|cs| self.status_stage_complete.borrow(cs).replace(false))
+    }
+
+    /// Returns `true` if a control transfer has been aborted by a new SETUP packet since the
+    /// last call.
+    ///
+    /// The host may start a new control transfer at any time, which aborts the one in progress.
+    /// The `usb-device` stack simply moves on to the new request, so class drivers that keep
+    /// state across the stages of a control transfer can use this to discard it.
+    ///
+    /// A control transfer is considered in progress from its SETUP stage until its status stage
+    /// completes or EP0 is stalled.
+    pub fn take_control_transfer_aborted(&self) -> bool {
+        interrupt::free(|cs| self.control_transfer_aborted.borrow(cs).replace(false))
+    }
+
+    /// Sets or clears the global OUT NAK.
+    ///
+    /// When setting, this waits until the NAK is effective, i.e. the core NAKs all OUT
+    /// transactions on all endpoints. Returns `UsbError::InvalidState` if it doesn't become
+    /// effective in time, the NAK stays requested in that case.
+    pub fn set_global_out_nak(&self, nak: bool) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            if nak {
+                if !crate::endpoint::set_global_out_nak(*regs) {
+                    return Err(UsbError::InvalidState);
+                }
+            } else {
+                crate::endpoint::clear_global_out_nak(*regs);
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets or clears the global IN NAK.
+    ///
+    /// When setting, this waits until the NAK is effective, i.e. the core NAKs all IN
+    /// transactions on all endpoints. Returns `UsbError::InvalidState` if it doesn't become
+    /// effective in time, the NAK stays requested in that case.
+    pub fn set_global_in_nak(&self, nak: bool) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            if nak {
+                if !crate::endpoint::set_global_in_nak(*regs) {
+                    return Err(UsbError::InvalidState);
+                }
+            } else {
+                crate::endpoint::clear_global_in_nak(*regs);
+            }
+            Ok(())
+        })
+    }
+
+    /// Sends the feedback value (Ff) of an asynchronous USB Audio endpoint on its isochronous
+    /// feedback IN endpoint.
+    ///
+    /// `samples_per_frame` is the number of samples per frame, or per microframe at high speed,
+    /// as a 16.16 fixed-point number. It is sent in the format required by the USB 2.0
+    /// specification (section 5.12.4.2) for the enumerated speed: 10.14 in 3 bytes at full speed,
+    /// 16.16 in 4 bytes at high speed. Like any isochronous packet, it is sent in the next
+    /// (micro)frame. Returns `UsbError::InvalidState` if the device hasn't been enumerated.
+    pub fn write_feedback(&self, ep_addr: EndpointAddress, samples_per_frame: u32) -> Result<usize> {
+        let (value, len) = match self.speed() {
+            Some(UsbSpeed::High) => (samples_per_frame, 4),
+            Some(_) => (samples_per_frame >> 2, 3),
+            None => return Err(UsbError::InvalidState),
+        };
+
+        usb_device::bus::UsbBus::write(self, ep_addr, &value.to_le_bytes()[..len])
+    }
+
+    /// Writes the data stage of a control IN transfer on EP0 with as few transfers as possible.
+    ///
+    /// Up to 3 packets, and at most 127 bytes, are queued as one multi-packet transfer, which
+    /// completes with a single `ep_in_complete` event. Returns the number of bytes written. Write
+    /// the rest of the data after the completion. Only full packets are written if `buf` doesn't
+    /// fit, so a long data stage isn't cut short.
+    ///
+    /// The control pipe of `usb-device` writes one packet at a time through `write`, so this is
+    /// meant for control transfers handled by the application itself.
+    pub fn write_control_data(&self, buf: &[u8]) -> Result<usize> {
+        let ep = self.allocator.endpoints_in[0].as_ref().ok_or(UsbError::InvalidEndpoint)?;
+
+        let len = ep.write_control_data(buf)?;
+        interrupt::free(|cs| self.ep0_in_zlp.borrow(cs).set(len == 0));
+        self.record_activity();
+
+        #[cfg(feature = "throughput")]
+        self.record_throughput(EndpointAddress::from_parts(0, UsbDirection::In), len);
+
+        Ok(len)
+    }
+
+    /// Writes a small (up to 8 bytes) packet to an IN endpoint with minimal register accesses.
+    ///
+    /// This is intended for frequent small interrupt transfers such as HID reports. The FIFO
+    /// space check is skipped when the endpoint is idle and the packet is known to fit. Other
+    /// packets and EP0 go through the regular write path.
+    pub fn write_small(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if ep_addr.index() == 0 {
+            return usb_device::bus::UsbBus::write(self, ep_addr, buf);
+        }
+
+        if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
+            ep.write_small(buf)?;
+            self.record_activity();
+
+            #[cfg(feature = "throughput")]
+            self.record_throughput(ep_addr, buf.len());
+
+            Ok(buf.len())
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    /// Returns the bus speed negotiated during the last enumeration.
+    ///
+    /// Returns `None` if the device hasn't been enumerated since the last bus reset.
+    pub fn speed(&self) -> Option<UsbSpeed> {
+        interrupt::free(|cs| self.speed.borrow(cs).get())
+    }
+
+    /// Reprograms the USB turnaround time (GUSBCFG.TRDT) for a new AHB frequency.
+    ///
+    /// The turnaround time is derived from `UsbPeripheral::ahb_frequency_hz` when the device
+    /// is enumerated. If the application changes the AHB clock afterwards, it has to report the
+    /// new frequency here, which also replaces the peripheral's frequency for later bus resets.
+    ///
+    /// The core uses TRDT while it sends data, so the update should happen between transfers,
+    /// e.g. while the bus is suspended or all IN endpoints are idle. A turnaround time that is
+    /// too long for the clock only costs throughput, while one that is too short can corrupt
+    /// packets: when lowering the clock, call this before the change, and when raising it, call
+    /// this after the change.
+    ///
+    /// Returns `UsbError::Unsupported` if the frequency is too low for the negotiated speed, or
+    /// for full speed if the device hasn't been enumerated yet.
+    pub fn update_clock(&self, ahb_frequency_hz: u32) -> Result<()> {
+        interrupt::free(|cs| {
+            let speed = self.speed.borrow(cs).get();
+            let min_frequency_hz = match speed {
+                Some(UsbSpeed::High) => HS_MIN_AHB_FREQUENCY_HZ,
+                _ => FS_MIN_AHB_FREQUENCY_HZ,
+            };
+            if ahb_frequency_hz < min_frequency_hz {
+                return Err(UsbError::Unsupported);
+            }
+
+            self.ahb_frequency_hz.borrow(cs).set(Some(ahb_frequency_hz));
+            if let Some(speed) = speed {
+                let regs = self.regs.borrow(cs);
+                let trdt = self.turnaround_time(speed, ahb_frequency_hz);
+                modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the AHB frequency last reported with `update_clock`, or by the peripheral
+    fn ahb_frequency_hz(&self, cs: &CriticalSection) -> u32 {
+        self.ahb_frequency_hz.borrow(cs).get()
+            .unwrap_or_else(|| self.peripheral.ahb_frequency_hz())
+    }
+
+    /// Returns the GUSBCFG.TRDT value for the enumerated speed and the AHB frequency
+    fn turnaround_time(&self, speed: UsbSpeed, ahb_frequency_hz: u32) -> u32 {
+        let trdt = match speed {
+            UsbSpeed::High => {
+                // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
+                if ahb_frequency_hz < HS_MIN_AHB_FREQUENCY_HZ {
+                    fatal!("AHB frequency is too low");
+                }
+                0x9
+            }
+            UsbSpeed::Full => {
+                // From RM0431 (F72xx), RM0090 (F429)
+                match ahb_frequency_hz {
+                    0..=14_199_999 => {
+                        fatal!("AHB frequency is too low");
+                        0xF
+                    }
+                    14_200_000..=14_999_999 => 0xF,
+                    15_000_000..=15_999_999 => 0xE,
+                    16_000_000..=17_199_999 => 0xD,
+                    17_200_000..=18_499_999 => 0xC,
+                    18_500_000..=19_999_999 => 0xB,
+                    20_000_000..=21_799_999 => 0xA,
+                    21_800_000..=23_999_999 => 0x9,
+                    24_000_000..=27_499_999 => 0x8,
+                    27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
+                    32_000_000..=u32::MAX => 0x6,
+                }
+            }
+            UsbSpeed::Low => {
+                // Low speed isn't supported in device mode
+                fatal!("Unsupported enumerated speed");
+                0xF
+            }
+        };
+        match (speed, self.peripheral.full_speed_turnaround_time()) {
+            (UsbSpeed::Full, Some(trdt)) => trdt as u32,
+            _ => trdt,
+        }
+    }
+
+    /// Returns `true` if the device is set up for high speed, but the last enumeration ended
+    /// at a lower speed.
+    ///
+    /// This usually indicates a failed chirp handshake during the bus reset, e.g. because of
+    /// signal integrity problems or a full-speed-only hub. The result is latched when the
+    /// enumeration completes, so disabling high speed afterwards doesn't change it.
+    pub fn hs_negotiation_failed(&self) -> bool {
+        interrupt::free(|cs| self.hs_negotiation_failed.borrow(cs).get())
+    }
+
+    /// Enables or disables high speed at runtime.
+    ///
+    /// With high speed disabled, a high-speed PHY is operated at full speed, so a single firmware
+    /// image built with the `hs` feature can serve boards that should run at either speed. The
+    /// PHY itself is selected by `UsbPeripheral::phy_type`, which can also be decided at runtime.
+    /// High speed is enabled by default, and this has no effect with a full-speed PHY.
+    ///
+    /// The setting is used from the next bus reset on. Call `force_reset` to make the host
+    /// re-enumerate the device at the new speed.
+    pub fn set_high_speed_enabled(&self, enabled: bool) {
+        interrupt::free(|cs| {
+            self.high_speed_enabled.borrow(cs).set(enabled);
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device(), DCFG, DSPD: self.device_speed(cs));
+        });
+    }
+
+    /// Returns `true` if high speed is enabled, see `set_high_speed_enabled`.
+    pub fn high_speed_enabled(&self) -> bool {
+        interrupt::free(|cs| self.high_speed_enabled.borrow(cs).get())
+    }
+
+    /// Puts the port into one of the USB 2.0 high-speed test modes (DCTL.TCTL).
+    ///
+    /// Call this after the status stage of the SET_FEATURE(TEST_MODE) request has completed.
+    /// Test modes are only defined at high speed, `Unsupported` is returned if the device hasn't
+    /// been enumerated at high speed. Per the specification, the device leaves a test mode only
+    /// through a power cycle, but `None` clears the field for bench use.
+    pub fn set_test_mode(&self, mode: Option<TestMode>) -> Result<()> {
+        if mode.is_some() && self.speed() != Some(UsbSpeed::High) {
+            return Err(UsbError::Unsupported);
+        }
+
+        let tctl = mode.map_or(0, |mode| mode as u32);
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            //modify_reg!(otg_device, regs.device(), DCTL, TCTL: tctl);
+            modify_reg!(otg_device, regs.device(), DCTL, |v| (v & !(0b111 << 4)) | (tctl << 4));
+        });
+        Ok(())
+    }
+
+    /// Starts transmitting the test packet for the Test_Packet compliance test (eye diagram).
+    ///
+    /// The core generates the standard test packet defined in section 7.1.20 of the USB 2.0
+    /// specification itself and repeats it until the device is powered off, so no endpoint or
+    /// FIFO has to be prepared. This is a shorthand for `set_test_mode(Some(TestMode::Packet))`.
+    pub fn start_test_packet(&self) -> Result<()> {
+        self.set_test_mode(Some(TestMode::Packet))
+    }
+
+    /// Selects whether the FIFOs and endpoints are configured by `poll` as soon as a bus reset
+    /// completes (ENUMDNE).
+    ///
+    /// A bus reset deconfigures all endpoints when it starts (USBRST). `poll` returns
+    /// `PollResult::Reset` when it completes, and by the `usb-device` contract the stack then
+    /// calls `UsbBus::reset`, which configures the FIFOs and endpoints again. Until then the
+    /// device NAKs everything, including a SETUP the host may already have sent. With this option
+    /// enabled, the configuration is done by `poll` before it returns `PollResult::Reset`, and the
+    /// following `UsbBus::reset` call only resets the device address and state. This is useful
+    /// with stacks that don't call `reset` promptly. A stack that doesn't call `reset` at all
+    /// still breaks the device address and state tracking.
+    ///
+    /// Disabled by default.
+    pub fn set_configure_on_enumeration(&self, enabled: bool) {
+        interrupt::free(|cs| self.configure_on_enumeration.borrow(cs).set(enabled));
+    }
+
+    /// Sets a function to be called when a bus reset is detected, or removes it with `None`.
+    ///
+    /// The callback is invoked from `poll` with interrupts disabled, after the endpoints have been
+    /// deconfigured and before `PollResult::Reset` is returned. It should only reset application
+    /// state and return quickly. It must not block and must not perform endpoint reads or writes.
+    pub fn set_reset_callback(&self, callback: Option<ResetCallback>) {
+        interrupt::free(|cs| self.on_reset.borrow(cs).set(callback));
+    }
+
+    /// Sets a function that sees every SETUP packet on EP0 before `usb-device`, or removes it
+    /// with `None`.
+    ///
+    /// The filter can claim a request by returning anything but `SetupAction::Pass`. The driver
+    /// then answers the request itself and hides it, including its data and status stages, from
+    /// `usb-device`. This allows handling quirks like Microsoft OS descriptors or vendor resets
+    /// without a custom class. Claimed IN requests are limited to what `write_control_data` can
+    /// send in one transfer, longer responses are stalled.
+    ///
+    /// The filter is invoked from `poll` with interrupts disabled, i.e. usually in interrupt
+    /// context. It must return quickly and must not perform endpoint reads or writes.
+    pub fn set_setup_filter(&self, filter: Option<SetupFilter>) {
+        interrupt::free(|cs| self.setup_filter.borrow(cs).set(filter));
+    }
+
+    /// Applies the SETUP filter to EP0 and hides the stages of claimed requests from `poll`
+    fn filter_setup(&self, cs: &CriticalSection, ep_out: &mut u16, ep_in_complete: &mut u16, ep_setup: &mut u16) {
+        let stage = self.filtered_stage.borrow(cs);
+
+        if *ep_in_complete & 1 != 0 && stage.get() != FilteredStage::None {
+            *ep_in_complete &= !1;
+            match stage.get() {
+                FilteredStage::StatusIn => stage.set(FilteredStage::None),
+                FilteredStage::DataInZlp => {
+                    // A short response that ends on a packet boundary needs a ZLP to end the data stage
+                    match &self.allocator.endpoints_in[0] {
+                        Some(ep) if ep.write(&[]).is_ok() => stage.set(FilteredStage::StatusOut),
+                        _ => {
+                            stage.set(FilteredStage::None);
+                            usb_device::bus::UsbBus::set_stalled(self, EndpointAddress::from_parts(0, UsbDirection::In), true);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if *ep_out & 1 != 0 && matches!(stage.get(), FilteredStage::DataInZlp | FilteredStage::StatusOut) {
+            // Status stage of a claimed IN request, the host may also end the data stage early
+            if let Some(ep) = &self.allocator.endpoints_out[0] {
+                ep.read(&mut [], true).ok();
+            }
+            *ep_out &= !1;
+            stage.set(FilteredStage::None);
+        }
+
+        if *ep_setup & 1 == 0 {
+            return;
+        }
+
+        // A new SETUP ends whatever control transfer was in progress
+        stage.set(FilteredStage::None);
+
+        let (filter, ep0_out, ep0_in) = match (
+            self.setup_filter.borrow(cs).get(),
+            &self.allocator.endpoints_out[0],
+            &self.allocator.endpoints_in[0],
+        ) {
+            (Some(filter), Some(ep0_out), Some(ep0_in)) => (filter, ep0_out, ep0_in),
+            _ => return,
+        };
+        let setup = match ep0_out.setup_packet(cs) {
+            Some(setup) => setup,
+            None => return,
+        };
+
+        // OUT data stages can't be received by the driver, such requests are stalled
+        let device_to_host = setup[0] & 0x80 != 0;
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        let respond = match filter(&setup) {
+            SetupAction::Pass => return,
+            SetupAction::Accept if device_to_host || length == 0 => Some(&[][..]),
+            SetupAction::Respond(data) if device_to_host => Some(&data[..core::cmp::min(data.len(), length)]),
+            _ => None,
+        };
+
+        ep0_out.read(&mut [0; 8], false).ok();
+        *ep_setup &= !1;
+
+        match respond {
+            Some(data) if ep0_in.write_control_data(data).ok() == Some(data.len()) => {
+                self.ep0_in_zlp.borrow(cs).set(data.is_empty());
+                let max_packet_size = ep0_in.active_max_packet_size(cs) as usize;
+                stage.set(if !device_to_host {
+                    FilteredStage::StatusIn
+                } else if !data.is_empty() && data.len() < length && data.len() % max_packet_size == 0 {
+                    FilteredStage::DataInZlp
+                } else {
+                    FilteredStage::StatusOut
+                });
+            }
+            _ => {
+                usb_device::bus::UsbBus::set_stalled(self, EndpointAddress::from_parts(0, UsbDirection::Out), true);
+                usb_device::bus::UsbBus::set_stalled(self, EndpointAddress::from_parts(0, UsbDirection::In), true);
+            }
+        }
+    }
+
+    /// Returns the number of words of endpoint memory allocated to OUT endpoint buffers.
+    pub fn used_rx_words(&self) -> usize {
+        self.allocator.memory_allocator.used_rx_words()
+    }
+
+    /// Returns the number of (micro)frames between the first bus reset and the end of the last
+    /// bus reset (ENUMDNE).
+    ///
+    /// Hosts usually reset the device several times while enumerating it, this measures the
+    /// whole sequence. Frames are counted with the SOF frame number of the host, so the first
+    /// bus reset itself isn't included. The measurement is only valid if it takes less than
+    /// 16384 microframes at high speed or 2048 frames at full speed. It restarts when the bus
+    /// is enabled again.
+    ///
+    /// Returns `None` until the first enumeration has completed.
+    pub fn enumeration_frames(&self) -> Option<u16> {
+        interrupt::free(|cs| self.enumeration.borrow(cs).get().frames)
+    }
+
+    /// Enables or disables the stuck enumeration watchdog.
+    ///
+    /// Marginal hardware sometimes detects a bus reset but never completes the enumeration
+    /// (ENUMDNE), which leaves the device unconfigured until it's unplugged. With the watchdog
+    /// enabled, the driver counts the SOFs received after each bus reset. If `frames` SOFs
+    /// arrive before the enumeration completes, all FIFOs are flushed and the condition is
+    /// reported by `take_enumeration_stuck`, after which the application should re-initialize
+    /// the core, e.g. with `core_soft_reset`.
+    ///
+    /// SOFs are sent every frame at full speed and every microframe at high speed, so the limit
+    /// should be chosen for the faster of the two. Hosts normally complete the enumeration right
+    /// after the reset, a limit of a few hundred frames is plenty. The SOF interrupt is unmasked
+    /// while waiting for the enumeration. The watchdog is disabled by default.
+    pub fn set_enumeration_watchdog(&self, frames: Option<u16>) {
+        interrupt::free(|cs| {
+            self.enumeration_watchdog.borrow(cs).set(EnumerationWatchdog {
+                limit: frames,
+                ..Default::default()
+            });
+            self.update_sof_mask(cs);
+        });
+    }
+
+    /// Returns `true` if the stuck enumeration watchdog has fired since the last call.
+    pub fn take_enumeration_stuck(&self) -> bool {
+        interrupt::free(|cs| {
+            let watchdog = self.enumeration_watchdog.borrow(cs);
+            let stuck = watchdog.get().stuck;
+            watchdog.set(EnumerationWatchdog { stuck: false, ..watchdog.get() });
+            stuck
+        })
+    }
+
+    /// Unmasks the SOF interrupt while the enumeration is being timed or watched
+    fn update_sof_mask(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let timer = self.enumeration.borrow(cs).get();
+        let timing = timer.reset_seen && timer.start_frame.is_none();
+        let watching = self.enumeration_watchdog.borrow(cs).get().frames.is_some();
+        modify_reg!(otg_global, regs.global(), GINTMSK, SOFM: (timing || watching) as u32);
+    }
+
+    /// Returns the total number of words of endpoint memory passed to the constructor.
+    pub fn rx_capacity_words(&self) -> usize {
+        self.allocator.memory_allocator.capacity_words()
+    }
+
+    /// Returns the number of (micro)frames elapsed since the last endpoint read or write.
+    ///
+    /// The frame counter of the core wraps every 16384 (micro)frames, so this has to be called,
+    /// or `poll` has to run, at least once per wrap period to keep the count accurate.
+    pub fn idle_frames(&self) -> u32 {
+        interrupt::free(|cs| self.update_idle(cs, false))
+    }
+
+    /// Mask of the DSTS.FNSOF bits that count at the enumerated speed. Before the enumeration
+    /// the widest counter the core supports is assumed.
+    fn frame_number_mask(&self, cs: &CriticalSection) -> u16 {
+        match self.speed.borrow(cs).get() {
+            Some(speed) => speed.frame_number_mask(),
+            None if USB::HIGH_SPEED => UsbSpeed::High.frame_number_mask(),
+            None => UsbSpeed::Full.frame_number_mask(),
+        }
+    }
+
+    fn update_idle(&self, cs: &CriticalSection, activity: bool) -> u32 {
+        let regs = self.regs.borrow(cs);
+        let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+        let frame_mask = self.frame_number_mask(cs);
+
+        let idle = self.idle.borrow(cs);
+        let mut tracker = idle.get();
+        tracker.update(frame_number, frame_mask, activity);
+        idle.set(tracker);
+        tracker.idle_frames
+    }
+
+    /// Enables or disables the detection of a silent host.
+    ///
+    /// A configured device should receive a SOF every (micro)frame until the host suspends the
+    /// bus. A host that crashed, or a marginal cable, can stop the SOFs without the core ever
+    /// detecting a suspend. With `checks` set, `check_host_silent` reports the host as silent
+    /// once it has been called `checks` times in a row without the SOF frame number advancing.
+    /// Disabled by default.
+    pub fn set_host_silence_limit(&self, checks: Option<u32>) {
+        interrupt::free(|cs| {
+            self.host_silence.borrow(cs).set(HostSilenceMonitor {
+                limit: checks,
+                ..Default::default()
+            });
+        });
+    }
+
+    /// Checks for SOFs since the last call and returns `true` if the host is silent.
+    ///
+    /// This has to be called periodically, e.g. from a timer, with a period of at least one
+    /// frame (1 ms) and well below the 16384 (micro)frames after which the frame number wraps.
+    /// Only the configured state is monitored, and a suspended bus doesn't count as silent.
+    /// Always returns `false` if the detection is disabled with `set_host_silence_limit`.
+    pub fn check_host_silent(&self) -> bool {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let (suspended, frame_number) = read_reg!(otg_device, regs.device(), DSTS, SUSPSTS, FNSOF);
+            let monitored = self.device_state.borrow(cs).get() == DeviceState::Configured && suspended == 0;
+
+            let silence = self.host_silence.borrow(cs);
+            let mut monitor = silence.get();
+            let silent = monitor.update(frame_number as u16, monitored);
+            silence.set(monitor);
+            silent
+        })
+    }
+
+    fn record_activity(&self) {
+        interrupt::free(|cs| {
+            self.update_idle(cs, true);
+        });
+    }
+
+    /// Overrides the size of the RX FIFO (GRXFSIZ).
+    ///
+    /// By default the RX FIFO is sized from the allocated OUT endpoint buffers, with a generous
+    /// margin. A `size_words` of 0 restores that size. The new size takes effect on the next USB
+    /// reset or `reconfigure_fifos` call.
+    ///
+    /// Returns `UsbError::BufferOverflow` if the size is below the minimum given by the reference
+    /// manuals for the allocated OUT endpoints, and `UsbError::EndpointMemoryOverflow` if the
+    /// resulting layout doesn't fit into the FIFO RAM.
+    pub fn set_rx_fifo_size_words(&self, size_words: u16) -> Result<()> {
+        if size_words != 0 && size_words < self.min_rx_fifo_size_words() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        interrupt::free(|cs| {
+            let rx_fifo_size = if size_words != 0 { size_words } else { self.auto_rx_fifo_size_words() };
+            if !self.check_fifo_layout(rx_fifo_size, &self.tx_fifo_sizes_words(cs)) {
+                return Err(UsbError::EndpointMemoryOverflow);
+            }
+
+            self.rx_fifo_size_override.borrow(cs).set(size_words);
+            Ok(())
+        })
+    }
+
+    /// Selects a precomputed FIFO layout, or returns to the computed one with `None`.
+    ///
+    /// A profile replaces both the sizes computed from the endpoint allocation and the overrides
+    /// of `set_rx_fifo_size_words` and `set_tx_fifo_size_words`. It takes effect on the next USB
+    /// reset or `reconfigure_fifos` call.
+    ///
+    /// Returns `UsbError::BufferOverflow` if the RX FIFO is below the minimum given by the
+    /// reference manuals or a TX FIFO is too small for the endpoints that use it, and
+    /// `UsbError::EndpointMemoryOverflow` if the layout doesn't fit into the FIFO RAM.
+    pub fn set_fifo_profile(&self, profile: Option<FifoProfile>) -> Result<()> {
+        if let Some(profile) = &profile {
+            if profile.rx_fifo_size_words < self.min_rx_fifo_size_words() {
+                return Err(UsbError::BufferOverflow);
+            }
+        }
+
+        interrupt::free(|cs| {
+            if let Some(profile) = &profile {
+                let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    let fifo = tx_fifo_map[ep.address().index()] as usize;
+                    if (profile.tx_fifo_sizes_words[fifo] as usize) * 4 < ep.max_packet_size() as usize {
+                        return Err(UsbError::BufferOverflow);
+                    }
+                }
+
+                if !self.check_fifo_layout(profile.rx_fifo_size_words, &profile.tx_fifo_sizes_words) {
+                    return Err(UsbError::EndpointMemoryOverflow);
+                }
+            }
+
+            self.fifo_profile.borrow(cs).set(profile);
+            Ok(())
+        })
+    }
+
+    /// Overrides the size of a TX FIFO.
+    ///
+    /// A `size_words` of 0 restores the size computed from the endpoint allocation. The new
+    /// size takes effect on the next USB reset or `reconfigure_fifos` call.
+    ///
+    /// Returns `UsbError::BufferOverflow` if the FIFO would be too small for the endpoints that
+    /// use it, and `UsbError::EndpointMemoryOverflow` if the resulting layout doesn't fit into
+    /// the FIFO RAM.
+    pub fn set_tx_fifo_size_words(&self, fifo: u8, size_words: u16) -> Result<()> {
+        if fifo as usize >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|cs| {
+            let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+            let mut overrides = self.tx_fifo_size_overrides.borrow(cs).get();
+
+            if size_words != 0 {
+                let max_packet_size = self.allocator.endpoints_in.iter().flatten()
+                    .filter(|ep| tx_fifo_map[ep.address().index()] == fifo)
+                    .map(|ep| ep.max_packet_size())
+                    .max()
+                    .unwrap_or(0);
+                if (size_words as usize) * 4 < max_packet_size as usize {
+                    return Err(UsbError::BufferOverflow);
+                }
+            }
+
+            overrides[fifo as usize] = size_words;
+            let tx_fifo_sizes = self.compute_tx_fifo_sizes_words(&tx_fifo_map, &overrides);
+            if !self.check_fifo_layout(self.rx_fifo_size_words(), &tx_fifo_sizes) {
+                return Err(UsbError::EndpointMemoryOverflow);
+            }
+
+            self.tx_fifo_size_overrides.borrow(cs).set(overrides);
+            Ok(())
+        })
+    }
+
+    /// Re-programs the FIFO layout at runtime.
+    ///
+    /// All transfers are NAKed while the layout is being changed, and all FIFOs are flushed, so
+    /// any packets that haven't been transmitted or read yet are lost. This is intended to be
+    /// called while the endpoints are idle, e.g. when switching alternate settings.
+    ///
+    /// Returns `UsbError::EndpointMemoryOverflow` if the layout doesn't fit into the FIFO RAM,
+    /// and `UsbError::InvalidState` if the core doesn't stop the transfers or flush the FIFOs in
+    /// time.
+    pub fn reconfigure_fifos(&self) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let rx_fifo_size = self.rx_fifo_size_words();
+            let tx_fifo_sizes = self.tx_fifo_sizes_words(cs);
+            if !self.check_fifo_layout(rx_fifo_size, &tx_fifo_sizes) {
+                return Err(UsbError::EndpointMemoryOverflow);
+            }
+
+            if !crate::endpoint::set_global_in_nak(*regs) || !crate::endpoint::set_global_out_nak(*regs) {
+                crate::endpoint::clear_global_out_nak(*regs);
+                crate::endpoint::clear_global_in_nak(*regs);
+                return Err(UsbError::InvalidState);
+            }
+
+            Self::write_fifo_layout(regs, rx_fifo_size, &tx_fifo_sizes);
+
+            // Flush Rx & Tx FIFOs
+            modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+            let flushed = spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) == (0, 0));
+            #[cfg(feature = "trace")]
+            if flushed {
+                self.trace_fifo_flushes(cs);
+            }
+
+            crate::endpoint::clear_global_out_nak(*regs);
+            crate::endpoint::clear_global_in_nak(*regs);
+
+            if flushed {
+                Ok(())
+            } else {
+                Err(UsbError::InvalidState)
+            }
+        })
+    }
+
+    /// Reads the device status in a single critical section.
+    pub fn device_status_register(&self) -> DeviceStatus {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let (suspended, speed, erratic_error, frame_number) = read_reg!(otg_device, regs.device(), DSTS,
+                SUSPSTS, ENUMSPD, EERR, FNSOF
+            );
+            let soft_disconnected = read_reg!(otg_device, regs.device(), DCTL, SDIS);
+
+            DeviceStatus {
+                suspended: suspended != 0,
+                enumerated_speed: UsbSpeed::from_enumspd(speed),
+                erratic_error: erratic_error != 0,
+                soft_disconnected: soft_disconnected != 0,
+                frame_number: frame_number as u16,
+            }
+        })
+    }
+
+    /// Returns `true` if the core moves endpoint data by DMA (GAHBCFG.DMAEN).
+    ///
+    /// The driver itself always uses the FIFOs directly (slave mode). The cores supported by the
+    /// `fs` feature have no DMA engine, so with that feature this is always `false`.
+    pub fn is_dma_enabled(&self) -> bool {
+        #[cfg(feature = "hs")]
+        {
+            interrupt::free(|cs| {
+                let regs = self.regs.borrow(cs);
+                read_reg!(otg_global, regs.global(), GAHBCFG, DMAEN) != 0
+            })
+        }
+        #[cfg(feature = "fs")]
+        {
+            false
+        }
+    }
+
+    /// Returns the number of (bidirectional) endpoints of the peripheral, including EP0.
+    ///
+    /// This is `UsbPeripheral::ENDPOINT_COUNT`, made available at runtime so class drivers can
+    /// adapt to the peripheral without depending on its type.
+    pub fn max_endpoints(&self) -> usize {
+        USB::ENDPOINT_COUNT
+    }
+
+    /// Returns the size of the FIFO RAM of the peripheral in 32-bit words.
+    ///
+    /// This is `UsbPeripheral::FIFO_DEPTH_WORDS`, shared between the RX FIFO and all TX FIFOs.
+    pub fn fifo_depth_words(&self) -> usize {
+        USB::FIFO_DEPTH_WORDS
+    }
+
+    /// Gives `f` access to the registers of an endpoint within a critical section.
+    ///
+    /// The direction of `ep_addr` selects the DIEPxxx or DOEPxxx registers. This is an escape
+    /// hatch for class drivers that need a register bit the driver doesn't expose, e.g. for a
+    /// hardware quirk. Returns `UsbError::InvalidEndpoint` if the endpoint number doesn't exist on
+    /// the peripheral.
+    ///
+    /// # Safety
+    ///
+    /// The driver keeps state that mirrors these registers, e.g. whether an endpoint is enabled
+    /// or stalled, and the transfer in progress. Changing bits the driver relies on can leave
+    /// it out of sync with the hardware and break transfers on the endpoint.
+    pub unsafe fn with_endpoint_registers<R>(
+        &self,
+        ep_addr: EndpointAddress,
+        f: impl FnOnce(&EndpointRegisters) -> R,
+    ) -> Result<R> {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        interrupt::free(|_| {
+            let offset = if ep_addr.is_in() { 0x900 } else { 0xb00 };
+            let registers = EndpointRegisters {
+                base: USB::REGISTERS as usize + offset + 0x20 * ep_addr.index(),
+            };
+            Ok(f(&registers))
+        })
+    }
+
+    /// Returns the raw value of the Synopsys ID register (GSNPSID).
+    ///
+    /// The upper half is always `0x4f54` ("OT"), the lower half is the core release number,
+    /// e.g. `0x4f54_281a` for release 2.81a.
+    pub fn synopsys_id(&self) -> u32 {
+        interrupt::free(|cs| self.regs.borrow(cs).gsnpsid().read())
+    }
+
+    /// Returns the BESL (best effort service latency) of the last LPM token (GLPMCFG.BESL).
+    ///
+    /// The host sends the BESL with each LPM (L1) request. It ranges from 0 to 15 and selects the
+    /// time the host drives resume signaling when leaving L1, from 125 µs for 0 to 10 ms for 15 as
+    /// given in the LPM ECN, which bounds how long the device may take to resume.
+    ///
+    /// The driver doesn't enable LPM (GLPMCFG.LPMEN) or handle L1 itself, so the core answers
+    /// LPM tokens only if the application has enabled it. Device-initiated wakeup from L1 isn't
+    /// implemented either: `signal_remote_wakeup` only leaves the L2 suspend state, and an
+    /// application that wakes the host from L1 has to time its resume from this value itself.
+    /// Returns `None` on cores without LPM support, like the F429, and while LPM is disabled.
+    pub fn lpm_besl(&self) -> Option<u8> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            if !is_f446_like_core(read_reg!(otg_global, regs.global(), CID)) {
+                return None;
+            }
+
+            let glpmcfg = regs.glpmcfg().read();
+            if glpmcfg & (1 << 0) == 0 {
+                // LPMEN
+                return None;
+            }
+            Some(((glpmcfg >> 2) & 0xf) as u8)
+        })
+    }
+
+    /// Reads up to `buf.len()` bytes of the packet received on an OUT endpoint.
+    ///
+    /// Unlike `read`, which returns `UsbError::BufferOverflow` for a packet that doesn't fit,
+    /// this reads as much as fits and leaves the rest for the following calls. The next packet
+    /// is accepted only after the current one has been read completely. Passing an empty buffer
+    /// discards the rest of the packet and returns `UsbError::BufferOverflow`.
+    pub fn read_partial(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.read_out(ep_addr, buf, true)
+    }
+
+    /// Returns the number of bytes of the last packet received on an OUT endpoint that haven't
+    /// been read yet, see `read_partial`.
+    pub fn read_remaining(&self, ep_addr: EndpointAddress) -> Result<usize> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        match &self.allocator.endpoints_out[ep_addr.index()] {
+            Some(ep) => Ok(ep.remaining()),
+            None => Err(UsbError::InvalidEndpoint),
+        }
+    }
+
+    /// Reads from an OUT endpoint for `read` and `read_partial`
+    fn read_out(&self, ep_addr: EndpointAddress, buf: &mut [u8], partial: bool) -> Result<usize> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
+            #[cfg(feature = "strict")]
+            interrupt::free(|cs| self.check_endpoint_access(cs, ep_addr, ep.state(cs)))?;
+
+            let size = ep.read(buf, partial)?;
+            self.record_activity();
+
+            #[cfg(feature = "throughput")]
+            self.record_throughput(ep_addr, size);
+
+            Ok(size)
+        } else {
+            Err(UsbError::InvalidEndpoint)
+        }
+    }
+
+    /// Returns the number of bytes written to an IN endpoint that the host hasn't acknowledged.
+    ///
+    /// Bytes still in the TX FIFO are counted in whole words, and a TX FIFO shared with other
+    /// IN endpoints also counts their bytes. A pending zero-length packet counts as 0 bytes, so
+    /// check that `endpoint_state` no longer reports `EndpointState::Active` before assuming
+    /// that everything has been delivered, e.g. before cutting the power to the peripheral.
+    pub fn tx_pending_bytes(&self, ep_addr: EndpointAddress) -> Result<u16> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        match &self.allocator.endpoints_in[ep_addr.index()] {
+            Some(ep) => interrupt::free(|cs| {
+                let regs = self.regs.borrow(cs);
+                let fifo = read_reg!(crate::ral::endpoint_in, regs.endpoint_in(ep_addr.index()), DIEPCTL, TXFNUM);
+                Ok(ep.pending_bytes(self.tx_fifo_sizes_words(cs)[fifo as usize]))
+            }),
+            None => Err(UsbError::InvalidEndpoint),
+        }
+    }
+
+    /// Returns how much of the transfer programmed into an endpoint is left.
+    ///
+    /// This reads the transfer size register of the endpoint, which is useful for finding out
+    /// where a transfer got stuck. Returns `UsbError::InvalidEndpoint` if the endpoint hasn't
+    /// been allocated.
+    pub fn endpoint_transfer_status(&self, ep_addr: EndpointAddress) -> Result<TransferStatus> {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let status = if ep_addr.is_in() {
+            self.allocator.endpoints_in[ep_addr.index()].as_ref().map(|ep| ep.transfer_status())
+        } else {
+            self.allocator.endpoints_out[ep_addr.index()].as_ref().map(|ep| ep.transfer_status())
+        };
+        status.ok_or(UsbError::InvalidEndpoint)
+    }
+
+    /// Writes a packet of `len` bytes to an IN endpoint straight from FIFO words.
+    ///
+    /// This is an escape hatch for throughput-critical code that keeps its data in 32-bit words,
+    /// it skips the byte packing done by `write`. `words` has to hold exactly the words pushed
+    /// into the TX FIFO, i.e. `len` rounded up to whole words, in the byte order of the core
+    /// (little-endian). The endpoint is programmed for the packet and its completion is reported
+    /// by `poll` like for `write`. EP0 isn't supported, since its writes are tracked by the
+    /// control transfer handling.
+    ///
+    /// `write_fifo` and `write` must not be used on the same endpoint at the same time, e.g.
+    /// from an interrupt handler and the main context. Calling it from the same context as
+    /// `poll` is always safe.
+    pub fn write_fifo(&self, ep_addr: EndpointAddress, words: &[u32], len: usize) -> Result<usize> {
+        if !ep_addr.is_in() || ep_addr.index() == 0 || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if words.len() != len.div_ceil(4) {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let ep = self.allocator.endpoints_in[ep_addr.index()].as_ref().ok_or(UsbError::InvalidEndpoint)?;
+        let result = ep.write_words(words, len);
+        if let Err(UsbError::WouldBlock) = result {
+            interrupt::free(|cs| self.wait_for_tx_fifo(cs, ep_addr.index()));
+        }
+        result?;
+
+        self.record_activity();
+        #[cfg(feature = "throughput")]
+        self.record_throughput(ep_addr, len);
+
+        Ok(len)
+    }
+
+    /// Reads the next packet received on an OUT endpoint straight from the RX FIFO into `words`.
+    ///
+    /// This is the receiving counterpart of `write_fifo`, it skips the endpoint buffer and the
+    /// byte unpacking done by `read`. The packet is copied as whole words, the bytes past the
+    /// returned length in the last word are undefined. EP0 isn't supported.
+    ///
+    /// `poll` pops the RX FIFO on its own while the RXFLVL interrupt is unmasked, so this
+    /// requires it to be masked with `set_rxflvl_enabled(false)` and returns
+    /// `UsbError::InvalidState` otherwise. Only the entry at the head of the RX FIFO can be read.
+    /// If it belongs to another endpoint, or the FIFO is empty, `UsbError::WouldBlock` is
+    /// returned, and the RXFLVL interrupt has to be unmasked again to let `poll` handle other
+    /// endpoints. Returns `UsbError::BufferOverflow`, leaving the packet in the FIFO, if `words`
+    /// is too short.
+    pub fn read_fifo(&self, ep_addr: EndpointAddress, words: &mut [u32]) -> Result<usize> {
+        if !ep_addr.is_out() || ep_addr.index() == 0 || ep_addr.index() >= USB::ENDPOINT_COUNT ||
+           self.allocator.endpoints_out[ep_addr.index()].is_none() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let count = interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM) != 0 {
+                return Err(UsbError::InvalidState);
+            }
+
+            let core_id = read_reg!(otg_global, regs.global(), CID);
+            loop {
+                if read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) == 0 {
+                    return Err(UsbError::WouldBlock);
+                }
+
+                let rx_status = decode_grxsts(read_reg!(otg_global, regs.global(), GRXSTSR));
+                if rx_status.epnum as usize != ep_addr.index() {
+                    return Err(UsbError::WouldBlock);
+                }
+
+                match rx_status.packet_status {
+                    PacketStatus::OutComplete => {
+                        // Re-enable the endpoint, F429-like chips only
+                        if is_f429_like_core(core_id) {
+                            self.rearm_out_endpoint(cs, rx_status.epnum);
+                        }
+                        self.pop_rx_status(cs);
+                    }
+                    PacketStatus::OutData => {
+                        let count = rx_status.byte_count as usize;
+                        let size_words = count.div_ceil(4);
+                        if size_words > words.len() {
+                            return Err(UsbError::BufferOverflow);
+                        }
+
+                        self.pop_rx_status(cs);
+                        #[cfg(feature = "trace")]
+                        self.trace(cs, TraceEvent::RxStatus {
+                            endpoint: rx_status.epnum,
+                            status: rx_status.packet_status,
+                            byte_count: rx_status.byte_count,
+                        });
+
+                        let fifo = regs.fifo(0);
+                        for word in &mut words[..size_words] {
+                            *word = fifo.read();
+                        }
+
+                        // Re-enable the endpoint, F446-like chips only
+                        if is_f446_like_core(core_id) {
+                            self.rearm_out_endpoint(cs, rx_status.epnum);
+                        }
+
+                        return Ok(count);
+                    }
+                    _ => return Err(UsbError::WouldBlock),
+                }
+            }
+        })?;
+
+        self.record_activity();
+        #[cfg(feature = "throughput")]
+        self.record_throughput(ep_addr, count);
+
+        Ok(count)
+    }
+
+    /// Borrows the packet received on an OUT endpoint in place, without copying it.
+    ///
+    /// The returned guard dereferences to the unread part of the packet. The endpoint buffer
+    /// isn't refilled while the guard is alive, and `read` returns `UsbError::WouldBlock`.
+    /// Dropping the guard releases the packet as if it had been read to the end, and the next
+    /// packet is accepted. Returns `UsbError::WouldBlock` if no packet is pending or it is already
+    /// borrowed. SETUP packets can't be borrowed.
+    pub fn borrow_packet(&self, ep_addr: EndpointAddress) -> Result<PacketGuard<'_>> {
+        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let ep = self.allocator.endpoints_out[ep_addr.index()].as_ref().ok_or(UsbError::InvalidEndpoint)?;
+
+        #[cfg(feature = "strict")]
+        interrupt::free(|cs| self.check_endpoint_access(cs, ep_addr, ep.state(cs)))?;
+
+        let (data, len) = interrupt::free(|cs| ep.borrow_packet(cs)).ok_or(UsbError::WouldBlock)?;
+        self.record_activity();
+
+        #[cfg(feature = "throughput")]
+        self.record_throughput(ep_addr, len);
+
+        Ok(PacketGuard { endpoint: ep, data, len })
+    }
+
+    /// Checks that the type and maximum packet size of an endpoint read back from DIEPCTLx or
+    /// DOEPCTLx match what the driver has configured.
+    ///
+    /// This is meant for bring-up on new silicon, to catch register writes that didn't take
+    /// effect. Returns `UsbError::InvalidEndpoint` if the endpoint hasn't been allocated, and
+    /// `UsbError::InvalidState` if it isn't configured or the registers don't match.
+    pub fn verify_endpoint(&self, ep_addr: EndpointAddress) -> Result<()> {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let matches = interrupt::free(|cs| {
+            if ep_addr.is_in() {
+                let ep = self.allocator.endpoints_in[ep_addr.index()].as_ref().ok_or(UsbError::InvalidEndpoint)?;
+                Ok(ep.state(cs) != EndpointState::Allocated && ep.verify(cs))
+            } else {
+                let ep = self.allocator.endpoints_out[ep_addr.index()].as_ref().ok_or(UsbError::InvalidEndpoint)?;
+                Ok(ep.state(cs) != EndpointState::Allocated && ep.verify(cs))
+            }
+        })?;
+
+        if matches {
+            Ok(())
+        } else {
+            Err(UsbError::InvalidState)
+        }
+    }
+
+    /// Returns the halt status of an endpoint for a GET_STATUS(ENDPOINT) request.
+    ///
+    /// Returns `UsbError::InvalidEndpoint` if the endpoint hasn't been allocated, in which case
+    /// the request should be answered with a STALL.
+    pub fn endpoint_halted(&self, ep_addr: EndpointAddress) -> Result<bool> {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let allocated = if ep_addr.is_in() {
+            self.allocator.endpoints_in[ep_addr.index()].is_some()
+        } else {
+            self.allocator.endpoints_out[ep_addr.index()].is_some()
+        };
+        if !allocated {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let regs = UsbRegisters::new::<USB>();
+        Ok(crate::endpoint::is_stalled(regs, ep_addr))
+    }
+
+    /// Sets or clears the STALL condition of both directions of an endpoint number.
+    ///
+    /// This is meant for control endpoints, whose IN and OUT halves are stalled together. Both
+    /// are changed within one critical section, so `poll` never sees just one of them stalled.
+    pub fn set_stalled_both(&self, number: u8, stalled: bool) {
+        interrupt::free(|_| {
+            for direction in [UsbDirection::Out, UsbDirection::In] {
+                let ep_addr = EndpointAddress::from_parts(number as usize, direction);
+                usb_device::bus::UsbBus::set_stalled(self, ep_addr, stalled);
+            }
+        });
+    }
+
+    /// Aborts a pending IN transfer, discarding its data.
+    ///
+    /// The endpoint is disabled using the NAK handshake and its TX FIFO is flushed, after which
+    /// it's ready for the next `write`. If the TX FIFO is shared with other IN endpoints, their
+    /// pending data is discarded as well. Does nothing if the endpoint has no pending transfer.
+    ///
+    /// Returns `UsbError::InvalidState` if the core doesn't disable the endpoint or flush the
+    /// FIFO in time.
+    pub fn abort_in(&self, ep_addr: EndpointAddress) -> Result<()> {
+        if !ep_addr.is_in() || ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        match &self.allocator.endpoints_in[ep_addr.index()] {
+            Some(ep) => {
+                interrupt::free(|cs| {
+                    if !ep.abort(cs) {
+                        return Err(UsbError::InvalidState);
+                    }
+                    self.clear_idle_zlp_pending(cs, ep_addr.index());
+                    if ep_addr.index() == 0 {
+                        self.ep0_in_zlp.borrow(cs).set(false);
+                    }
+                    Ok(())
+                })
+            }
+            None => Err(UsbError::InvalidEndpoint),
+        }
+    }
+
+    /// Checks the allocated endpoints against the endpoints of the device descriptors.
+    ///
+    /// Every listed endpoint has to be allocated with the same direction, type and maximum
+    /// packet size, and every allocated endpoint other than EP0 has to be listed. EP0 may be
+    /// listed to check its packet size. This is meant to be called once at startup, after all
+    /// classes have been created, to catch descriptors that have drifted from the allocation.
+    ///
+    /// Returns the first mismatch found, checking the listed endpoints in order before looking
+    /// for unlisted ones.
+    pub fn check_endpoints(&self, expected: &[ExpectedEndpoint]) -> core::result::Result<(), EndpointMismatch> {
+        for endpoint in expected {
+            let address = endpoint.address;
+            let index = address.index();
+            let allocated = if index >= USB::ENDPOINT_COUNT {
+                None
+            } else if address.is_in() {
+                self.allocator.endpoints_in[index].as_ref().map(|ep| (ep.ep_type(), ep.max_packet_size()))
+            } else {
+                self.allocator.endpoints_out[index].as_ref().map(|ep| (ep.ep_type(), ep.max_packet_size()))
+            };
+
+            match allocated {
+                None => return Err(EndpointMismatch::NotAllocated(address)),
+                Some((ep_type, _)) if ep_type != endpoint.ep_type => {
+                    return Err(EndpointMismatch::Type {
+                        address,
+                        expected: endpoint.ep_type,
+                        allocated: ep_type,
+                    });
+                }
+                Some((_, max_packet_size)) if max_packet_size != endpoint.max_packet_size => {
+                    return Err(EndpointMismatch::MaxPacketSize {
+                        address,
+                        expected: endpoint.max_packet_size,
+                        allocated: max_packet_size,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let allocated = self.allocator.endpoints_in.iter().flatten().map(|ep| ep.address())
+            .chain(self.allocator.endpoints_out.iter().flatten().map(|ep| ep.address()));
+        for address in allocated {
+            if address.index() != 0 && !expected.iter().any(|endpoint| endpoint.address == address) {
+                return Err(EndpointMismatch::Unexpected(address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Switches endpoints to the settings of a new alternate interface setting.
+    ///
+    /// Each listed endpoint is disabled, discarding any pending IN data, and re-enabled with the
+    /// new maximum packet size if it's part of the new setting. The packet size can't exceed the
+    /// one the endpoint has been allocated with, since the buffers and FIFOs are sized for it.
+    /// EP0 can't be switched and keeps working throughout.
+    ///
+    /// All settings are validated before any endpoint is touched, so on error the endpoints are
+    /// left as they were. Returns `UsbError::InvalidEndpoint` for EP0 or endpoints that haven't
+    /// been allocated, and `UsbError::EndpointMemoryOverflow` if a packet size is too large.
+    ///
+    /// `UsbError::InvalidState` is returned if a pending transfer couldn't be stopped in time.
+    /// The switch stops at that endpoint and the endpoints already switched get their previous
+    /// packet size and configured state back. The endpoint that couldn't be stopped is left
+    /// deconfigured if its disable failed, since re-configuring it could enable it twice.
+    pub fn set_alternate_setting(&self, endpoints: &[AltEndpointSetting]) -> Result<()> {
+        for setting in endpoints {
+            let index = setting.address.index();
+            if index == 0 || index >= USB::ENDPOINT_COUNT {
+                return Err(UsbError::InvalidEndpoint);
+            }
+
+            let allocated_max_packet_size = if setting.address.is_in() {
+                self.allocator.endpoints_in[index].as_ref().map(|ep| ep.max_packet_size())
+            } else {
+                self.allocator.endpoints_out[index].as_ref().map(|ep| ep.max_packet_size())
+            };
+            match (allocated_max_packet_size, setting.max_packet_size) {
+                (None, _) => return Err(UsbError::InvalidEndpoint),
+                (Some(allocated), Some(requested)) if requested > allocated => {
+                    return Err(UsbError::EndpointMemoryOverflow);
+                }
+                _ => {}
+            }
+        }
+
+        interrupt::free(|cs| {
+            let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+            // Packet size and configured state of the endpoints switched so far
+            let mut previous_in: [Option<(u16, bool)>; 9] = [None; 9];
+            let mut previous_out: [Option<(u16, bool)>; 9] = [None; 9];
+
+            for setting in endpoints {
+                let index = setting.address.index();
+                let mut stopped = true;
+                if setting.address.is_in() {
+                    if let Some(ep) = &self.allocator.endpoints_in[index] {
+                        let previous = (ep.active_max_packet_size(cs), ep.is_configured(cs));
+                        stopped = ep.abort(cs);
+                        if stopped {
+                            self.clear_idle_zlp_pending(cs, index);
+                            ep.deconfigure(cs);
+                            if let Some(max_packet_size) = setting.max_packet_size {
+                                ep.set_active_max_packet_size(cs, max_packet_size);
+                                ep.configure(cs, tx_fifo_map[index]);
+                            }
+                            previous_in[index].get_or_insert(previous);
+                        }
+                    }
+                } else if let Some(ep) = &self.allocator.endpoints_out[index] {
+                    let previous = (ep.active_max_packet_size(cs), ep.is_configured(cs));
+                    stopped = ep.deconfigure(cs);
+                    if stopped {
+                        if let Some(max_packet_size) = setting.max_packet_size {
+                            ep.set_active_max_packet_size(cs, max_packet_size);
+                            ep.configure(cs);
+                        }
+                        previous_out[index].get_or_insert(previous);
+                    }
+                }
+
+                if !stopped {
+                    self.restore_alternate_setting(cs, &previous_in, &previous_out);
+                    return Err(UsbError::InvalidState);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Gives the endpoints switched by a failed `set_alternate_setting` their previous packet
+    /// size and configured state back. An OUT endpoint that can't be disabled again is left
+    /// deconfigured.
+    fn restore_alternate_setting(
+        &self,
+        cs: &CriticalSection,
+        previous_in: &[Option<(u16, bool)>; 9],
+        previous_out: &[Option<(u16, bool)>; 9],
+    ) {
+        let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+
+        for (index, previous) in previous_in.iter().enumerate() {
+            if let (Some(ep), Some((max_packet_size, configured))) = (&self.allocator.endpoints_in[index], previous) {
+                // Switched in the same critical section, so no transfer can be pending
+                ep.deconfigure(cs);
+                ep.set_active_max_packet_size(cs, *max_packet_size);
+                if *configured {
+                    ep.configure(cs, tx_fifo_map[index]);
+                }
+            }
+        }
+
+        for (index, previous) in previous_out.iter().enumerate() {
+            if let (Some(ep), Some((max_packet_size, configured))) = (&self.allocator.endpoints_out[index], previous) {
+                let disabled = ep.deconfigure(cs);
+                ep.set_active_max_packet_size(cs, *max_packet_size);
+                if *configured && disabled {
+                    ep.configure(cs);
+                }
+            }
+        }
+    }
+
+    /// Returns all endpoints to a clean state without a bus reset.
+    ///
+    /// Pending IN transfers are aborted, all FIFOs are flushed and received packets that haven't
+    /// been read are discarded. Endpoints other than EP0 are re-configured, which clears their
+    /// STALL condition and resets their data toggle to DATA0. OUT endpoints, including EP0, are
+    /// re-armed afterwards. The device address and configured state are not affected.
+    ///
+    /// This is intended for recovering from a protocol error that left the class driver out of
+    /// sync with the host on several endpoints. The host has to be told about the reset by other
+    /// means, e.g. by a class-specific request, since its data toggles are not reset.
+    ///
+    /// Returns `UsbError::InvalidState` if the core doesn't stop a transfer or flush the FIFOs in
+    /// time. The endpoints are re-configured and re-armed anyway.
+    pub fn reset_all_endpoints(&self) -> Result<()> {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let tx_fifo_map = self.tx_fifo_map.borrow(cs).get();
+            let mut done = true;
+
+            for ep in self.allocator.endpoints_in.iter().flatten() {
+                done &= ep.abort(cs);
+                self.clear_idle_zlp_pending(cs, ep.address().index());
+                if ep.address().index() != 0 {
+                    ep.deconfigure(cs);
+                }
+            }
+            for ep in self.allocator.endpoints_out.iter().flatten() {
+                if ep.address().index() != 0 {
+                    done &= ep.deconfigure(cs);
+                }
+            }
+
+            // Flush Rx & Tx FIFOs, the RX FIFO can only be flushed while the global OUT NAK is effective
+            if crate::endpoint::set_global_out_nak(*regs) {
+                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+                done &= spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) == (0, 0));
+                #[cfg(feature = "trace")]
+                self.trace_fifo_flushes(cs);
+            } else {
+                done = false;
+            }
+            crate::endpoint::clear_global_out_nak(*regs);
+
+            for ep in self.allocator.endpoints_in.iter().flatten() {
+                let index = ep.address().index();
+                if index != 0 {
+                    ep.configure(cs, tx_fifo_map[index]);
+                }
+            }
+            for ep in self.allocator.endpoints_out.iter().flatten() {
+                ep.clear_buffers(cs);
+                if ep.address().index() != 0 {
+                    ep.configure(cs);
+                }
+                ep.rearm(cs);
+            }
+
+            if done {
+                Ok(())
+            } else {
+                Err(UsbError::InvalidState)
+            }
+        })
+    }
+
+    /// Returns the state of an endpoint, or `None` if it hasn't been allocated.
+    pub fn endpoint_state(&self, ep_addr: EndpointAddress) -> Option<EndpointState> {
+        if ep_addr.index() >= USB::ENDPOINT_COUNT {
+            return None;
+        }
+        interrupt::free(|cs| {
+            if ep_addr.is_in() {
+                self.allocator.endpoints_in[ep_addr.index()].as_ref().map(|ep| ep.state(cs))
+            } else {
+                self.allocator.endpoints_out[ep_addr.index()].as_ref().map(|ep| ep.state(cs))
+            }
+        })
+    }
+
+    /// Reads back the FIFO layout programmed into the core.
+    ///
+    /// Only the first `USB::ENDPOINT_COUNT` TX FIFOs are reported, the rest are left empty.
+    pub fn fifo_layout(&self) -> FifoLayout {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            let mut layout = FifoLayout {
+                rx_fifo_size_words: read_reg!(otg_global, regs.global(), GRXFSIZ, RXFD) as u16,
+                tx_fifos: [TxFifoLayout::default(); 9],
+            };
+
+            #[cfg(feature = "fs")]
+            let (depth, start) = read_reg!(otg_global, regs.global(), DIEPTXF0, TX0FD, TX0FSA);
+            #[cfg(feature = "hs")]
+            let (depth, start) = read_reg!(otg_global, regs.global(), GNPTXFSIZ, TX0FD, TX0FSA);
+            layout.tx_fifos[0] = TxFifoLayout {
+                start_address_words: start as u16,
+                depth_words: depth as u16,
+            };
+
+            for (i, fifo) in layout.tx_fifos.iter_mut().enumerate().take(USB::ENDPOINT_COUNT).skip(1) {
+                let (depth, start) = read_reg!(otg_global_dieptxfx, regs.dieptxfx(i), DIEPTXFx, INEPTXFD, INEPTXSA);
+                *fifo = TxFifoLayout {
+                    start_address_words: start as u16,
+                    depth_words: depth as u16,
+                };
+            }
+
+            layout
+        })
+    }
+
+    /// Sets the power source of the device.
+    ///
+    /// Bus-powered devices must not draw more than 2.5 mA from VBUS while suspended. For such
+    /// devices the driver stops the PHY clock and gates the core clock on suspend, leaving only
+    /// the resume and reset detection logic running, and restores the clocks on resume. The pull-up
+    /// stays connected. The current drawn by the rest of the MCU is up to the application, refer
+    /// to the datasheet of the MCU for the current consumption of the USB peripheral.
+    ///
+    /// When measuring the suspend current on VBUS, expect the D+ pull-up alone to draw about
+    /// 200 µA (3.3 V across the 1.5 kΩ pull-up and the 15 kΩ pull-down of the host), plus the
+    /// standby current of the gated peripheral from the datasheet. Anything beyond that comes
+    /// from the rest of the board. `poll` briefly restores the clocks to read the interrupt
+    /// status while they are gated, and gates them again if the bus is still suspended.
+    ///
+    /// Self-powered devices (the default) keep the clocks running while suspended.
+    pub fn set_power_source(&self, power_source: PowerSource) {
+        interrupt::free(|cs| self.power_source.borrow(cs).set(power_source));
+    }
+
+    /// Returns the power source of the device.
+    pub fn power_source(&self) -> PowerSource {
+        interrupt::free(|cs| self.power_source.borrow(cs).get())
+    }
+
+    /// Reports whether the host has enabled remote wakeup for the device.
+    ///
+    /// This should follow the DEVICE_REMOTE_WAKEUP feature state of the `usb-device` stack.
+    pub fn set_remote_wakeup_enabled(&self, enabled: bool) {
+        interrupt::free(|cs| self.remote_wakeup_enabled.borrow(cs).set(enabled));
+    }
+
+    /// Returns `true` if the host has enabled remote wakeup for the device.
+    pub fn remote_wakeup_enabled(&self) -> bool {
+        interrupt::free(|cs| self.remote_wakeup_enabled.borrow(cs).get())
+    }
+
+    /// Returns the status word for a GET_STATUS(DEVICE) request.
+    ///
+    /// Bit 0 is set for self-powered devices and bit 1 is set if the host has enabled remote
+    /// wakeup, all other bits are zero.
+    pub fn device_status(&self) -> u16 {
+        interrupt::free(|cs| {
+            let mut status = 0;
+            if self.power_source.borrow(cs).get() == PowerSource::SelfPowered {
+                status |= 1 << 0;
+            }
+            if self.remote_wakeup_enabled.borrow(cs).get() {
+                status |= 1 << 1;
+            }
+            status
+        })
+    }
+
+    /// Signals remote wakeup to the host.
+    ///
+    /// The clocks are restored if they have been gated on suspend, and the resume signaling is
+    /// driven on the bus for 5 ms. Interrupts are only disabled while DCTL.RWUSIG is set and
+    /// cleared, not during the delay. This wakes the host from the L2 suspend state only, waking
+    /// it from LPM L1 isn't supported (see `lpm_besl`).
+    ///
+    /// Returns `UsbError::InvalidState` if the device isn't suspended, the host hasn't enabled
+    /// remote wakeup or a remote wakeup is already being signaled.
+    pub fn signal_remote_wakeup(&self, delay: &mut impl DelayMs<u32>) -> Result<()> {
+        interrupt::free(|cs| {
+            if !self.suspended.borrow(cs).get() || !self.remote_wakeup_enabled.borrow(cs).get() {
+                return Err(UsbError::InvalidState);
+            }
+
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_device, regs.device(), DCTL, RWUSIG) != 0 {
+                return Err(UsbError::InvalidState);
+            }
+
+            self.clock_gating_pending.borrow(cs).set(false);
+            Self::ungate_clocks(regs);
+
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 1);
+            Ok(())
+        })?;
+
+        delay.delay_ms(5);
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            modify_reg!(otg_device, regs.device(), DCTL, RWUSIG: 0);
+
+            // The host takes over the resume signaling
+            self.start_resume(cs);
+        });
+
+        Ok(())
+    }
+
+    /// Returns `true` if the bus is suspended, i.e. between `PollResult::Suspend` and
+    /// `PollResult::Resume`.
+    pub fn is_suspended(&self) -> bool {
+        interrupt::free(|cs| self.suspended.borrow(cs).get())
+    }
+
+    /// Returns `true` while the bus is being resumed.
+    ///
+    /// This covers the remote wakeup signaling of the device (DCTL.RWUSIG) and the resume
+    /// signaling of the host, which ends with the first SOF after the resume. Data written to an
+    /// IN endpoint before that may be lost, so applications should wait for this to return
+    /// `false` before the first transfer after a resume. The end of the resume is detected from
+    /// the SOF frame number, so this has to be called again to notice it.
+    pub fn is_resuming(&self) -> bool {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_device, regs.device(), DCTL, RWUSIG) != 0 {
+                return true;
+            }
+
+            let resume_frame = self.resume_frame.borrow(cs);
+            match resume_frame.get() {
+                Some(frame) if read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16 == frame => true,
+                Some(_) => {
+                    resume_frame.set(None);
+                    false
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Starts tracking a resume until the next SOF
+    fn start_resume(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+        self.resume_frame.borrow(cs).set(Some(frame_number));
+    }
+
+    /// Returns the low-power state of the core as reported by PCGCCTL.
+    ///
+    /// This is intended for debugging suspend and resume issues.
+    pub fn low_power_state(&self) -> LowPowerState {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let pcgcctl = read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL);
+            LowPowerState::from_pcgcctl(pcgcctl)
+        })
+    }
+
+    /// Puts the PHY into suspend and gates the core clock.
+    ///
+    /// The PHY clock has to be stopped before the core clock is gated, otherwise the core can't
+    /// complete the PHY suspend handshake.
+    fn gate_clocks(regs: &UsbRegisters) {
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 1);
+
+        // Give the PHY a chance to enter suspend before the core clock goes away
+        let mut timeout = 1000;
+        while timeout > 0 && read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, PHYSUSP) == 0 {
+            timeout -= 1;
+        }
+
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 1);
+    }
+
+    /// Gates the clocks on a pending suspend once no received packets are left in the RX FIFO.
+    ///
+    /// The core clock must not be gated while `poll` still has to pop packets from the RX FIFO.
+    /// Pending IN packets stay in their TX FIFOs and are sent after resume.
+    fn gate_clocks_if_idle(&self, cs: &CriticalSection) {
+        if !self.clock_gating_pending.borrow(cs).get() {
+            return;
+        }
+
+        let regs = self.regs.borrow(cs);
+        let rx_pending = read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) != 0 &&
+                         read_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM) != 0;
+        if !rx_pending {
+            self.clock_gating_pending.borrow(cs).set(false);
+
+            // Stop the PHY clock and gate the core clock, resume and reset are still detected
+            Self::gate_clocks(regs);
+        }
+    }
+
+    /// Restores the clocks in the reverse order of `gate_clocks`.
+    fn ungate_clocks(regs: &UsbRegisters) {
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK: 0);
+        modify_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, STPPCLK: 0);
+    }
+
+    /// Re-enables an OUT endpoint after a packet has been received.
+    ///
+    /// EP0 OUT has to be re-armed from scratch after every stage of a control transfer, since
+    /// the transfer size, packet count and SETUP packet count are all used up by then. Other
+    /// endpoints keep their transfer size and data toggle.
+    fn rearm_out_endpoint(&self, cs: &CriticalSection, epnum: u8) {
+        match self.allocator.endpoints_out.get(epnum as usize) {
+            Some(Some(ep)) if epnum == 0 => ep.rearm(cs),
+            _ => {
+                use crate::ral::endpoint_out;
+
+                let regs = self.regs.borrow(cs);
+                let ep = regs.endpoint_out(epnum as usize);
+                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
+            }
+        }
+    }
+
+    /// Returns `true` if EP0 has received more back-to-back SETUP packets than it can buffer
+    /// (DOEPINT0.B2BSTUP) since the last call.
+    ///
+    /// `poll` recovers from this by discarding pending EP0 IN data and re-arming EP0 OUT for
+    /// SETUP packets, so control transfers keep working with a non-conformant host or a noisy
+    /// bus. The control transfer that was in progress is lost.
+    pub fn take_setup_overflow(&self) -> bool {
+        interrupt::free(|cs| self.setup_overflow.borrow(cs).replace(false))
+    }
+
+    /// Recovers EP0 from a SETUP packet overflow, returns `false` if disabling EP0 IN or the TX
+    /// FIFO flush timed out
+    fn recover_setup_overflow(&self, cs: &CriticalSection) -> bool {
+        let regs = self.regs.borrow(cs);
+        self.setup_overflow.borrow(cs).set(true);
+
+        // The FIFO must not be flushed while EP0 IN may still be fetching from it
+        if let Some(ep) = &self.allocator.endpoints_in[0] {
+            if !ep.abort(cs) {
+                return false;
+            }
+        }
+
+        modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: 0, TXFFLSH: 1);
+        #[cfg(feature = "trace")]
+        self.trace(cs, TraceEvent::TxFifoFlush(0));
+        if !spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 0) {
+            return false;
+        }
+        self.ep0_in_zlp.borrow(cs).set(false);
+        self.filtered_stage.borrow(cs).set(FilteredStage::None);
+        self.control_transfer_active.borrow(cs).set(false);
+        self.control_data_in.borrow(cs).set(false);
+
+        self.rearm_out_endpoint(cs, 0);
+        true
+    }
+
+    /// Resets the driver state after a bus reset or a disconnect.
+    ///
+    /// Returns `false` if an OUT endpoint didn't disable or the RX FIFO flush didn't complete in
+    /// time.
+    fn tear_down(&self, cs: &CriticalSection) -> bool {
+        let regs = self.regs.borrow(cs);
+
+        // A reset also ends the suspend state
+        Self::ungate_clocks(regs);
+        self.suspended.borrow(cs).set(false);
+        self.clock_gating_pending.borrow(cs).set(false);
+        self.resume_frame.borrow(cs).set(None);
+
+        let disabled = self.deconfigure_all(cs);
+
+        self.speed.borrow(cs).set(None);
+        self.hs_negotiation_failed.borrow(cs).set(false);
+        self.configured_at_enumeration.borrow(cs).set(false);
+        self.in_idle_zlp_pending.borrow(cs).set(0);
+        self.tx_refill_waiting.borrow(cs).set(0);
+        self.tx_refill_ready.borrow(cs).set(0);
+        write_reg!(otg_device, regs.device(), DIEPEMPMSK, 0);
+        self.control_transfer_active.borrow(cs).set(false);
+        self.filtered_stage.borrow(cs).set(FilteredStage::None);
+        self.control_transfer_aborted.borrow(cs).set(false);
+
+        // Flush RX
+        modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
+        #[cfg(feature = "trace")]
+        self.trace(cs, TraceEvent::RxFifoFlush);
+        let flushed = spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 0);
+
+        if let Some(callback) = self.on_reset.borrow(cs).get() {
+            callback();
+        }
+
+        disabled && flushed
+    }
+
+    /// Returns the raw value of the last RX FIFO status entry popped by the driver (GRXSTSP).
+    ///
+    /// Use `decode_grxsts` to decode it. Returns 0 until the first entry has been popped.
+    pub fn last_grxsts(&self) -> u32 {
+        interrupt::free(|cs| self.last_grxsts.borrow(cs).get())
+    }
+
+    /// Pops the RX FIFO status entry at the head of the RX FIFO
+    fn pop_rx_status(&self, cs: &CriticalSection) {
+        let regs = self.regs.borrow(cs);
+        self.last_grxsts.borrow(cs).set(read_reg!(otg_global, regs.global(), GRXSTSP));
+        self.poll_handled.borrow(cs).set(true);
+    }
+
+    /// Returns `true` if the last `poll` serviced at least one interrupt source.
+    ///
+    /// A `poll` that returns `PollResult::None` may still have made progress, e.g. by popping an
+    /// RX FIFO entry or acknowledging an endpoint interrupt. If this returns `false` while the
+    /// USB interrupt keeps firing, some unmasked interrupt isn't handled by the driver and the
+    /// ISR would loop forever.
+    pub fn poll_handled_interrupt(&self) -> bool {
+        interrupt::free(|cs| self.poll_handled.borrow(cs).get())
+    }
+
+    /// Returns the pending interrupts that can trigger the USB interrupt (GINTSTS, DAINT)
+    fn pending_interrupts(&self, cs: &CriticalSection) -> (u32, u32) {
+        let regs = self.regs.borrow(cs);
+        let global = read_reg!(otg_global, regs.global(), GINTSTS) & read_reg!(otg_global, regs.global(), GINTMSK);
+        let endpoints = read_reg!(otg_device, regs.device(), DAINT) & read_reg!(otg_device, regs.device(), DAINTMSK);
+        (global, endpoints)
+    }
+
+    /// Returns the number of bus resets seen by `poll`. The counter wraps around.
+    pub fn reset_count(&self) -> u32 {
+        interrupt::free(|cs| self.reset_count.borrow(cs).get())
+    }
+
+    /// Enables or disables the bus reset rate limit.
+    ///
+    /// A bad cable or a babbling device on the same hub can make the host reset the device over
+    /// and over, and each reset tears down and reconfigures all endpoints. With a limit set, the
+    /// driver disconnects the device from the bus when more than `limit.resets` resets arrive
+    /// within `limit.frames` (micro)frames, and reports it with `take_reset_storm`. The
+    /// application decides when to try again by calling `connect`.
+    ///
+    /// Frames are counted with the SOF frame number, which doesn't advance while the host keeps
+    /// the bus in reset, so resets without SOFs in between count as a single frame. Disabled by
+    /// default.
+    pub fn set_reset_rate_limit(&self, limit: Option<ResetRateLimit>) {
+        interrupt::free(|cs| {
+            self.reset_rate.borrow(cs).set(ResetRateTracker { limit, ..Default::default() });
+        });
+    }
+
+    /// Enables or disables queueing of link state events, see `next_event`.
+    ///
+    /// `poll` reports one lifecycle result per call, so a transition is easily missed when two
+    /// happen between calls, e.g. a short suspend. With the queue enabled, `poll` also records
+    /// every transition it handles, in order, in a small ring buffer. The queue is cleared.
+    /// Disabled by default.
+    pub fn set_event_queue_enabled(&self, enabled: bool) {
+        interrupt::free(|cs| {
+            self.events.borrow(cs).set(EventQueue { enabled, ..Default::default() });
+        });
+    }
+
+    /// Returns the oldest queued link state event, see `set_event_queue_enabled`.
+    pub fn next_event(&self) -> Option<UsbEvent> {
+        interrupt::free(|cs| {
+            let queue = self.events.borrow(cs);
+            let mut state = queue.get();
+            let event = state.pop();
+            queue.set(state);
+            event
+        })
+    }
+
+    /// Returns `true` if events have been dropped because the queue was full since the last call.
+    pub fn take_event_queue_overflow(&self) -> bool {
+        interrupt::free(|cs| {
+            let queue = self.events.borrow(cs);
+            let overflow = queue.get().overflow;
+            queue.set(EventQueue { overflow: false, ..queue.get() });
+            overflow
+        })
+    }
+
+    fn queue_event(&self, cs: &CriticalSection, event: UsbEvent) {
+        let queue = self.events.borrow(cs);
+        let mut state = queue.get();
+        state.push(event);
+        queue.set(state);
+    }
+
+    /// Returns `true` if the device has been disconnected by the reset rate limit since the last
+    /// call.
+    pub fn take_reset_storm(&self) -> bool {
+        interrupt::free(|cs| {
+            let tracker = self.reset_rate.borrow(cs);
+            let storm = tracker.get().storm;
+            tracker.set(ResetRateTracker { storm: false, ..tracker.get() });
+            storm
+        })
+    }
+
+    /// Counts a bus reset, returns `true` if the reset rate limit has been exceeded
+    fn count_reset(&self, cs: &CriticalSection) -> bool {
+        let count = self.reset_count.borrow(cs);
+        count.set(count.get().wrapping_add(1));
+
+        let tracker = self.reset_rate.borrow(cs);
+        let mut state = tracker.get();
+        let limit = match state.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+
+        let regs = self.regs.borrow(cs);
+        let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+        let frame_mask = self.frame_number_mask(cs);
+        if state.resets == 0 || frame_number.wrapping_sub(state.window_start) & frame_mask > limit.frames {
+            state.window_start = frame_number;
+            state.resets = 0;
+        }
+        state.resets = state.resets.saturating_add(1);
+
+        let exceeded = state.resets > limit.resets;
+        if exceeded {
+            state.resets = 0;
+            state.storm = true;
+        }
+        tracker.set(state);
+        exceeded
+    }
+
+    /// Returns the number of times `poll` found an OUT token received for a disabled OUT
+    /// endpoint (DOEPINT.OTEPDIS).
+    ///
+    /// The core NAKs such tokens, so no data is lost, but a growing count while the endpoint is
+    /// expected to be enabled points at a missed re-arm. OTEPDIS is a sticky status bit that
+    /// `poll` samples once per call, so any number of tokens between two calls counts once. The
+    /// counter wraps around.
+    pub fn out_token_disabled_count(&self) -> u32 {
+        interrupt::free(|cs| self.out_token_disabled_count.borrow(cs).get())
+    }
+
+    /// Sets the number of `poll` calls that saw NAKed OUT tokens after which an OUT endpoint is
+    /// flagged.
+    ///
+    /// The core NAKs OUT tokens while the endpoint buffer holds a packet that hasn't been read.
+    /// With a limit set, `poll` counts per endpoint the calls that found such tokens
+    /// (DOEPINT.OTEPDIS) since the last packet was accepted, and flags endpoints at or above the
+    /// limit, see `take_out_nak_limit_exceeded`. This points at data that the application never
+    /// consumes. The status bit doesn't raise an interrupt, so the count only advances while
+    /// `poll` is called for other events, and tokens between two calls count once. `None`
+    /// disables the monitoring, which is the default, and `Some(0)` flags an endpoint on the
+    /// first NAKed token. The counts are reset.
+    pub fn set_out_nak_limit(&self, limit: Option<u32>) {
+        interrupt::free(|cs| {
+            self.out_naks.borrow(cs).set(OutNakMonitor { limit, ..Default::default() });
+        });
+    }
+
+    /// Returns the number of `poll` calls that saw NAKed OUT tokens on an OUT endpoint since it
+    /// last accepted a packet, see `set_out_nak_limit`. Always 0 while the monitoring is disabled.
+    pub fn out_nak_count(&self, ep_addr: EndpointAddress) -> u32 {
+        if !ep_addr.is_out() {
+            return 0;
+        }
+        interrupt::free(|cs| self.out_naks.borrow(cs).get().counts.get(ep_addr.index()).copied().unwrap_or(0))
+    }
+
+    /// Returns the number of TX FIFO underruns on an IN endpoint (DIEPINT.TXFIFOUDRN).
+    ///
+    /// An underrun means that the core started sending a packet before the application had
+    /// written all of it to the TX FIFO, and the host received a corrupted or short packet. The
+    /// core only starts early with thresholding enabled (DTHRCTL), which the driver leaves
+    /// disabled, and FS cores don't report underruns at all, so the count normally stays at 0.
+    /// Choppy isochronous streams without underruns rather point at IN data that missed its
+    /// frame, which `poll` discards (GINTSTS.IISOIXFR). The counter wraps around.
+    pub fn in_underrun_count(&self, ep_addr: EndpointAddress) -> u32 {
+        if !ep_addr.is_in() {
+            return 0;
+        }
+        interrupt::free(|cs| self.in_underruns.borrow(cs).get().get(ep_addr.index()).copied().unwrap_or(0))
+    }
+
+    /// Returns a bitmask of the OUT endpoints that have been at or above the NAK limit since the
+    /// last call, bit N standing for endpoint N. See `set_out_nak_limit`.
+    pub fn take_out_nak_limit_exceeded(&self) -> u16 {
+        interrupt::free(|cs| {
+            let monitor = self.out_naks.borrow(cs);
+            let exceeded = monitor.get().exceeded;
+            monitor.set(OutNakMonitor { exceeded: 0, ..monitor.get() });
+            exceeded
+        })
+    }
+
+    /// Counts a poll that saw NAKed OUT tokens on an OUT endpoint, see `set_out_nak_limit`
+    fn count_out_nak(&self, cs: &CriticalSection, index: usize) {
+        let monitor = self.out_naks.borrow(cs);
+        let mut state = monitor.get();
+        if let Some(limit) = state.limit {
+            let count = state.counts[index].saturating_add(1);
+            state.counts[index] = count;
+            if count >= limit {
+                state.exceeded |= 1 << index;
+            }
+            monitor.set(state);
+        }
+    }
+
+    /// Returns `true` if `poll` has given up waiting for the core since the last call.
+    ///
+    /// `poll` bounds its waits for FIFO flushes and endpoint disables. If the core doesn't
+    /// respond in time, `poll` returns `PollResult::None` instead of hanging and sets this flag.
+    /// The core is likely in a bad state, and should be reinitialized, e.g. by `force_reset`.
+    pub fn take_poll_error(&self) -> bool {
+        interrupt::free(|cs| self.poll_error.borrow(cs).replace(false))
+    }
+
+    #[cfg(feature = "throughput")]
+    /// Returns the throughput measured on an endpoint since the last `reset_throughput` call.
+    ///
+    /// Time is measured in (micro)frames using the SOF frame number, so no system timer is
+    /// required. Returns `None` for invalid endpoint addresses.
+    pub fn throughput(&self, ep_addr: EndpointAddress) -> Option<ThroughputStats> {
+        interrupt::free(|cs| {
+            let throughput = self.throughput.borrow(cs);
+            let mut counters = throughput.get();
+            counters.stats(ep_addr)
+        })
+    }
+
+    #[cfg(feature = "throughput")]
+    /// Resets the throughput counters of an endpoint.
+    pub fn reset_throughput(&self, ep_addr: EndpointAddress) {
+        interrupt::free(|cs| {
+            let throughput = self.throughput.borrow(cs);
+            let mut counters = throughput.get();
+            counters.reset(ep_addr);
+            throughput.set(counters);
+        })
+    }
+
+    #[cfg(feature = "throughput")]
+    fn record_throughput(&self, ep_addr: EndpointAddress, bytes: usize) {
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+            let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF);
+            let frame_mask = self.frame_number_mask(cs);
+
+            let throughput = self.throughput.borrow(cs);
+            let mut counters = throughput.get();
+            counters.record(ep_addr, frame_number, frame_mask, bytes);
+            throughput.set(counters);
+        })
+    }
+
+    #[cfg(feature = "trace")]
+    /// Copies the newest trace records into `out`, oldest first, and returns their number.
+    ///
+    /// The trace buffer keeps the last `trace::TRACE_LEN` significant events handled by the
+    /// driver, like interrupts, RX FIFO entries and FIFO flushes, along with the SOF frame number.
+    /// It's meant to be dumped after a failure to see what led to it.
+    pub fn copy_trace(&self, out: &mut [TraceRecord]) -> usize {
+        interrupt::free(|cs| self.trace.borrow(cs).borrow().copy_to(out))
+    }
+
+    #[cfg(feature = "trace")]
+    /// Discards all trace records.
+    pub fn clear_trace(&self) {
+        interrupt::free(|cs| self.trace.borrow(cs).borrow_mut().clear())
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace(&self, cs: &CriticalSection, event: TraceEvent) {
+        let regs = self.regs.borrow(cs);
+        let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+        self.trace.borrow(cs).borrow_mut().record(frame_number, event);
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace_fifo_flushes(&self, cs: &CriticalSection) {
+        self.trace(cs, TraceEvent::RxFifoFlush);
+        self.trace(cs, TraceEvent::TxFifoFlush(0x10));
+    }
+
+    /// Checks that an allocated endpoint can be read from or written to in the current state
+    #[cfg(feature = "strict")]
+    fn check_endpoint_access(&self, cs: &CriticalSection, ep_addr: EndpointAddress, state: EndpointState) -> Result<()> {
+        if state == EndpointState::Allocated {
+            return Err(UsbError::InvalidState);
+        }
+
+        // EP0 is stalled to reject a request and accepts the next SETUP regardless. The other
+        // endpoints are only used once the host has addressed the device.
+        if ep_addr.index() != 0 {
+            if self.device_state.borrow(cs).get() == DeviceState::Default {
+                return Err(UsbError::InvalidState);
+            }
+            if crate::endpoint::is_stalled(*self.regs.borrow(cs), ep_addr) {
+                return Err(UsbError::InvalidState);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the DIEPINTx events of an IN endpoint, returns its bit if a transfer completed
+    fn service_in_endpoint(&self, cs: &CriticalSection, ep: &EndpointIn) -> u16 {
+        use crate::ral::endpoint_in;
+
+        let regs = self.regs.borrow(cs);
+        let ep_regs = regs.endpoint_in(ep.address().index());
+        let bit = 1 << ep.address().index();
+        let mut ep_in_complete = 0;
+
+        if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
+            write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
+            #[cfg(feature = "trace")]
+            self.trace(cs, TraceEvent::InComplete(ep.address()));
+
+            let idle_zlp_pending = self.in_idle_zlp_pending.borrow(cs);
+            if idle_zlp_pending.get() & bit != 0 {
+                // The driver's own ZLP, the class didn't write anything
+                idle_zlp_pending.set(idle_zlp_pending.get() & !bit);
+            } else {
+                ep_in_complete |= bit;
+            }
+
+            if ep.address().index() == 0 && self.ep0_in_zlp.borrow(cs).replace(false) &&
+               is_status_stage(self.control_data_in.borrow(cs).get(), UsbDirection::In) {
+                // Zero-length IN on EP0 has been ACKed, the status stage is complete
+                self.status_stage_complete.borrow(cs).set(true);
+                self.control_transfer_active.borrow(cs).set(false);
+            }
+        }
+
+        if read_reg!(endpoint_in, ep_regs, DIEPINT, ITTXFE) != 0 {
+            write_reg!(endpoint_in, ep_regs, DIEPINT, ITTXFE: 1);
+
+            // The host has polled an idle endpoint, answer the next poll with a ZLP
+            if self.in_idle_zlp.borrow(cs).get() & bit != 0 &&
+               read_reg!(endpoint_in, ep_regs, DIEPCTL, EPENA) == 0 &&
+               ep.write(&[]).is_ok() {
+                let idle_zlp_pending = self.in_idle_zlp_pending.borrow(cs);
+                idle_zlp_pending.set(idle_zlp_pending.get() | bit);
+            }
+        }
+
+        //if read_reg!(endpoint_in, ep_regs, DIEPINT, TXFIFOUDRN) != 0 {
+        if read_reg!(endpoint_in, ep_regs, DIEPINT) & (1 << 8) != 0 {
+            //write_reg!(endpoint_in, ep_regs, DIEPINT, TXFIFOUDRN: 1);
+            write_reg!(endpoint_in, ep_regs, DIEPINT, 1 << 8);
+
+            let underruns = self.in_underruns.borrow(cs);
+            let mut counts = underruns.get();
+            let count = &mut counts[ep.address().index()];
+            *count = count.wrapping_add(1);
+            underruns.set(counts);
+        }
+
+        // Acknowledge the other unmasked events, the driver doesn't act on them. Any of them
+        // left set keeps the DAINT bit of the endpoint and GINTSTS.IEPINT asserted.
+        let unhandled = read_reg!(endpoint_in, ep_regs, DIEPINT) & read_reg!(otg_device, regs.device(), DIEPMSK);
+        if unhandled != 0 {
+            write_reg!(endpoint_in, ep_regs, DIEPINT, unhandled);
+        }
+
+        ep_in_complete
+    }
+
+    /// Handles the DOEPINTx events of an OUT endpoint, returns the endpoint's `ep_out` bit if a
+    /// transfer completed
+    ///
+    /// A completed transfer (XFRC) reports the packet that was just received. Packets that
+    /// haven't been read by the next `poll` are reported again from the endpoint buffer.
+    fn service_out_endpoint(&self, cs: &CriticalSection, ep: &EndpointOut) -> u16 {
+        use crate::ral::endpoint_out;
+
+        let regs = self.regs.borrow(cs);
+        let ep_regs = regs.endpoint_out(ep.address().index());
+        let pending = read_reg!(endpoint_out, ep_regs, DOEPINT) & read_reg!(otg_device, regs.device(), DOEPMSK);
+
+        if pending & endpoint_out::DOEPINT::STUP::mask != 0 {
+            // The SETUP stage is over, also if the SetupComplete entry hasn't been seen yet
+            ep.buffer.borrow(cs).borrow_mut().complete_setup();
+        }
+
+        // Any event left set keeps the DAINT bit of the endpoint and GINTSTS.OEPINT asserted
+        if pending != 0 {
+            write_reg!(endpoint_out, ep_regs, DOEPINT, pending);
+        }
+
+        if pending & endpoint_out::DOEPINT::XFRC::mask != 0 {
+            1 << ep.address().index()
+        } else {
+            0
+        }
+    }
+
+    /// Handles the entry at the head of the RX FIFO, returns `true` if it has been popped
+    ///
+    /// Received OUT data is reported by the transfer completion, see `service_out_endpoint`.
+    /// Returns `Err` if the core didn't respond in time.
+    fn service_rx_entry(&self, cs: &CriticalSection, core_id: u32) -> core::result::Result<bool, ()> {
+        use crate::ral::endpoint_in;
+
+        let regs = self.regs.borrow(cs);
+        let rx_status = decode_grxsts(read_reg!(otg_global, regs.global(), GRXSTSR));
+        let epnum = rx_status.epnum;
+        #[cfg(feature = "trace")]
+        self.trace(cs, TraceEvent::RxStatus {
+            endpoint: epnum,
+            status: rx_status.packet_status,
+            byte_count: rx_status.byte_count,
+        });
+        match rx_status.packet_status {
+            PacketStatus::OutData => {
+                if epnum == 0 && rx_status.byte_count == 0 &&
+                   is_status_stage(self.control_data_in.borrow(cs).get(), UsbDirection::Out) {
+                    // Zero-length OUT on EP0 completes the status stage
+                    self.status_stage_complete.borrow(cs).set(true);
+                    self.control_transfer_active.borrow(cs).set(false);
+                }
+            }
+            PacketStatus::SetupData => {
+                // flushing TX if something stuck in control endpoint
+                let ep = regs.endpoint_in(epnum as usize);
+                if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
+                    modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum as u32, TXFFLSH: 1);
+                    #[cfg(feature = "trace")]
+                    self.trace(cs, TraceEvent::TxFifoFlush(epnum));
+                    if !spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 0) {
+                        return Err(());
+                    }
+                }
+                // Reported after the SETUP stage completes
+            }
+            PacketStatus::OutComplete => {
+                // End of the data stage, re-enable the endpoint, F429-like chips only
+                if is_f429_like_core(core_id) {
+                    self.rearm_out_endpoint(cs, epnum);
+                }
+                self.pop_rx_status(cs);
+            }
+            PacketStatus::SetupComplete => {
+                // Re-enable the endpoint, F429-like chips only
+                if is_f429_like_core(core_id) {
+                    self.rearm_out_endpoint(cs, epnum);
+                }
+                self.pop_rx_status(cs);
+
+                // The SETUP stage is over, hand the SETUP packet to the control handler
+                if let Some(Some(ep)) = self.allocator.endpoints_out.get(epnum as usize) {
+                    ep.buffer.borrow(cs).borrow_mut().complete_setup();
+                }
+
+                // A new SETUP aborts the control transfer in progress
+                if epnum == 0 && self.control_transfer_active.borrow(cs).replace(true) {
+                    self.control_transfer_aborted.borrow(cs).set(true);
+                }
+            }
+            PacketStatus::GlobalOutNak | PacketStatus::Reserved(_) => {
+                self.pop_rx_status(cs);
+            }
+        }
+
+        let is_setup = rx_status.packet_status == PacketStatus::SetupData;
+        if rx_status.packet_status == PacketStatus::OutData || is_setup {
+            let mut popped = false;
+            if let Some(Some(ep)) = self.allocator.endpoints_out.get(epnum as usize) {
+                if ep.can_accept(cs, is_setup) {
+                    self.pop_rx_status(cs);
+                    popped = true;
+
+                    ep.fill_from_fifo(cs, *regs, rx_status.byte_count, is_setup);
+
+                    if let Some(request_type) = ep.setup_request_type(cs) {
+                        self.control_data_in.borrow(cs).set(has_data_in(request_type));
+                    }
+
+                    // The endpoint makes progress again
+                    let naks = self.out_naks.borrow(cs);
+                    let mut monitor = naks.get();
+                    monitor.counts[epnum as usize] = 0;
+                    naks.set(monitor);
+
+                    // Re-enable the endpoint, F446-like chips only
+                    if is_f446_like_core(core_id) {
+                        self.rearm_out_endpoint(cs, epnum);
+                    }
+                }
+            }
+            return Ok(popped);
+        }
+
+        Ok(true)
+    }
+
+    fn set_poll_error(&self, cs: &CriticalSection) {
+        self.poll_error.borrow(cs).set(true);
+        #[cfg(feature = "trace")]
+        self.trace(cs, TraceEvent::PollError);
+    }
+
+    #[cfg(feature = "hs")]
+    /// Reads from a ULPI register in an external ULPI PHY.
+    ///
+    /// Interrupts are disabled for the duration of the function call.
+    ///
+    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`. With the `panic-free`
+    /// feature `UlpiError::Unsupported` is returned instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use synopsys_usb_otg::{UsbPeripheral, UsbBus};
+    /// fn read_usb_vid_pid<USB: UsbPeripheral>(bus: &UsbBus<USB>) -> (u16, u16) {
+    ///     let mut vid: u16 = bus.ulpi_read(0x00) as u16;
+    ///     vid |= (bus.ulpi_read(0x01) as u16) << 8;
+    ///     let mut pid: u16 = bus.ulpi_read(0x02) as u16;
+    ///     pid |= (bus.ulpi_read(0x03) as u16) << 8;
+    ///     (vid, pid)
+    /// }
+    /// ```
+    pub fn ulpi_read(&self, addr: u8) -> core::result::Result<u8, UlpiError> {
+        if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
+            fatal!("ulpi_read is only supported with external ULPI PHYs");
+            return Err(UlpiError::Unsupported);
+        }
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // Begin ULPI register read transaction
+            modify_reg!(otg_global, regs.global(), PHYCR,
+                NEW: 1,
+                RW: 0,
+                ADDR: addr as u32
+            );
+
+            // ULPI transactions should take less than 1us. 1000 iterations should be enough even for fast MCUs.
+            let mut timeout = 1000;
+            while timeout > 0 {
+                // Wait for transaction to complete
+                if read_reg!(otg_global, regs.global(), PHYCR, DONE) != 0 {
+                    // Read transaction data
+                    return Ok((read_reg!(otg_global, regs.global(), PHYCR, DATA) & 0xFF) as u8);
+                }
+                timeout -= 1;
+            }
+            Err(UlpiError::Timeout)
+        })
+    }
+
+    #[cfg(feature = "hs")]
+    /// Writes to a ULPI register in an external ULPI PHY.
+    ///
+    /// Interrupts are disabled for the duration of the function call.
+    ///
+    /// **Panics:** if `phy_type` is not `PhyType::ExternalHighSpeed`. With the `panic-free`
+    /// feature `UlpiError::Unsupported` is returned instead.
+    pub fn ulpi_write(&self, addr: u8, data: u8) -> core::result::Result<(), UlpiError> {
+        if self.peripheral.phy_type() != PhyType::ExternalHighSpeed {
+            fatal!("ulpi_write is only supported with external ULPI PHYs");
+            return Err(UlpiError::Unsupported);
+        }
+
+        interrupt::free(|cs| {
+            let regs = self.regs.borrow(cs);
+
+            // Begin ULPI register write transaction
+            modify_reg!(otg_global, regs.global(), PHYCR,
+                NEW: 1,
+                RW: 1,
+                ADDR: addr as u32,
+                DATA: data as u32
+            );
+
+            // ULPI transactions should take less than 1us. 1000 iterations should be enough even for fast MCUs.
+            let mut timeout = 1000;
+            while timeout > 0 {
+                // Wait for transaction to complete
+                if read_reg!(otg_global, regs.global(), PHYCR, DONE) != 0 {
+                    return Ok(());
+                }
+                timeout -= 1;
+            }
+            Err(UlpiError::Timeout)
+        })
+    }
+}
+
+#[cfg(feature = "hs")]
+#[derive(Debug)]
+/// Errors that can occur while interfacing with a ULPI PHY.
+pub enum UlpiError {
+    /// The action has timed out.
+    Timeout,
+    /// The PHY isn't an external ULPI PHY.
+    Unsupported,
+}
+
+/// IN endpoint interrupt sources, see `UsbBus::set_in_endpoint_interrupts`.
+///
+/// The default unmasks only the transfer completed interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InEndpointInterrupts {
+    /// Transfer completed (DIEPMSK.XFRCM). The driver relies on it to report IN completion,
+    /// masking it stops `poll` from reporting completed IN transfers.
+    pub transfer_completed: bool,
+    /// Endpoint disabled (DIEPMSK.EPDM), raised when an endpoint disable requested by the
+    /// application has taken effect.
+    pub endpoint_disabled: bool,
+    /// Timeout condition (DIEPMSK.TOM), raised when the host doesn't acknowledge an IN packet
+    /// on a non-isochronous endpoint.
+    pub timeout: bool,
+    /// IN token received when TX FIFO is empty (DIEPMSK.ITTXFEMSK).
+    pub token_received_tx_fifo_empty: bool,
+    /// IN token received with endpoint mismatch (DIEPMSK.INEPNMM).
+    pub token_mismatch: bool,
+    /// IN endpoint NAK effective (DIEPMSK.INEPNEM), raised when a NAK requested by setting
+    /// DIEPCTL.SNAK has taken effect.
+    pub nak_effective: bool,
+}
+
+impl Default for InEndpointInterrupts {
+    fn default() -> Self {
+        Self {
+            transfer_completed: true,
+            endpoint_disabled: false,
+            timeout: false,
+            token_received_tx_fifo_empty: false,
+            token_mismatch: false,
+            nak_effective: false,
+        }
+    }
+}
+
+/// OUT endpoint interrupt sources, see `UsbBus::set_out_endpoint_interrupts`.
+///
+/// The default unmasks the transfer completed and SETUP phase done interrupts. The packets
+/// themselves are received through the RX FIFO interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct OutEndpointInterrupts {
+    /// Transfer completed (DOEPMSK.XFRCM).
+    pub transfer_completed: bool,
+    /// Endpoint disabled (DOEPMSK.EPDM), raised when an endpoint disable requested by the
+    /// application has taken effect.
+    pub endpoint_disabled: bool,
+    /// SETUP phase done (DOEPMSK.STUPM), raised when the SETUP stage of a control transfer
+    /// has completed.
+    pub setup_done: bool,
+    /// OUT token received when endpoint disabled (DOEPMSK.OTEPDM).
+    pub token_received_endpoint_disabled: bool,
+}
+
+impl Default for OutEndpointInterrupts {
+    fn default() -> Self {
+        Self {
+            transfer_completed: true,
+            endpoint_disabled: false,
+            setup_done: true,
+            token_received_endpoint_disabled: false,
+        }
+    }
+}
+
+/// Endpoint settings of an alternate interface setting, see `UsbBus::set_alternate_setting`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AltEndpointSetting {
+    /// Endpoint address.
+    pub address: EndpointAddress,
+    /// Maximum packet size, or `None` if the endpoint isn't used by the alternate setting.
+    pub max_packet_size: Option<u16>,
+}
+
+/// An endpoint as declared by the device descriptors, see `UsbBus::check_endpoints` and
+/// `FifoProfile::compute`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ExpectedEndpoint {
+    /// Endpoint address, giving the number and direction.
+    pub address: EndpointAddress,
+    /// Transfer type.
+    pub ep_type: EndpointType,
+    /// Maximum packet size (wMaxPacketSize).
+    pub max_packet_size: u16,
+}
+
+/// Difference between the allocated endpoints and the descriptors, see
+/// `UsbBus::check_endpoints`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndpointMismatch {
+    /// The endpoint is declared, but hasn't been allocated.
+    NotAllocated(EndpointAddress),
+    /// The endpoint has been allocated, but isn't declared.
+    Unexpected(EndpointAddress),
+    /// The endpoint has been allocated with a different transfer type.
+    Type {
+        /// Endpoint address.
+        address: EndpointAddress,
+        /// Transfer type in the descriptors.
+        expected: EndpointType,
+        /// Transfer type the endpoint has been allocated with.
+        allocated: EndpointType,
+    },
+    /// The endpoint has been allocated with a different maximum packet size.
+    MaxPacketSize {
+        /// Endpoint address.
+        address: EndpointAddress,
+        /// Maximum packet size in the descriptors.
+        expected: u16,
+        /// Maximum packet size the endpoint has been allocated with.
+        allocated: u16,
+    },
+}
+
+/// VBUS detection, see `UsbBus::set_vbus_detection`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VbusDetection {
+    /// VBUS isn't sensed, the device always assumes that it's attached.
+    Disabled,
+    /// VBUS is sensed on the VBUS pin by the internal comparator.
+    Internal(VbusThreshold),
+    /// VBUS is sensed by external circuitry, e.g. a GPIO behind a board-specific divider, and
+    /// reported with `UsbBus::set_vbus_valid`.
+    External,
+}
+
+/// Threshold of the internal VBUS comparator.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VbusThreshold {
+    /// B-device session valid threshold (GCCFG.VBUSBSEN), 0.8 V to 4 V on STM32F4.
+    SessionValid,
+    /// A-device VBUS valid threshold (GCCFG.VBUSASEN), 4.4 V to 4.75 V on STM32F4.
+    VbusValid,
+}
+
+/// Details of an endpoint or FIFO layout that doesn't fit, see `UsbPeripheral::fifo_overflow`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FifoOverflow {
+    /// The endpoint being allocated, or `None` if the complete FIFO layout doesn't fit.
+    pub endpoint: Option<EndpointAddress>,
+    /// Number of words requested.
+    pub requested_words: usize,
+    /// Number of words that were available, either in the FIFO RAM or in the endpoint memory
+    /// passed to the constructor.
+    pub available_words: usize,
+}
+
+/// A core that doesn't look like the one described by `UsbPeripheral`, see
+/// `UsbPeripheral::core_mismatch`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CoreMismatch {
+    /// GSNPSID doesn't hold the Synopsys OTG ID, the raw value is included.
+    UnknownId(u32),
+    /// `UsbPeripheral::HIGH_SPEED` is set, but GHWCFG2 reports no high-speed PHY interface.
+    NoHighSpeedPhy,
+}
+
+/// Behavior of an IN endpoint that is polled with no data written, see
+/// `UsbBus::set_in_idle_policy`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InIdlePolicy {
+    /// NAK the IN token, the host retries later.
+    Nak,
+    /// Answer with a zero-length packet.
+    ZeroLengthPacket,
+}
+
+/// Refill point of an IN endpoint's TX FIFO, see `UsbBus::set_tx_refill_policy`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TxRefillPolicy {
+    /// Retry when the TX FIFO is completely empty.
+    Empty,
+    /// Retry when the TX FIFO is half empty.
+    HalfEmpty,
+}
+
+/// Lifecycle state of an allocated endpoint.
+///
+/// Endpoints are `Allocated` when `usb-device` builds the device, `Configured` once they have
+/// been programmed into the core by a bus reset and `Active` while the core has them enabled
+/// (EPENA) for a transfer. A deconfigured endpoint, e.g. after the device has been disconnected
+/// or torn down, returns to `Allocated` and keeps its number and buffer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndpointState {
+    /// The endpoint has a number and a buffer but isn't programmed into the core.
+    Allocated,
+    /// The endpoint is programmed into the core and idle.
+    Configured,
+    /// The endpoint is enabled for a transfer.
+    Active,
+}
+
+/// Remaining part of the transfer programmed into an endpoint, decoded from its DIEPTSIZx or
+/// DOEPTSIZx register.
+///
+/// The core decrements both counters as packets are sent or received. They keep their last
+/// value once the transfer has completed or the endpoint has been disabled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TransferStatus {
+    /// Number of packets left (PKTCNT).
+    pub packets: u16,
+    /// Number of bytes left (XFRSIZ).
+    pub bytes: u32,
+}
+
+/// Bus reset rate limit, see `UsbBus::set_reset_rate_limit`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ResetRateLimit {
+    /// Number of resets allowed within the window.
+    pub resets: u16,
+    /// Length of the window in (micro)frames, at most 16383 microframes at high speed and
+    /// 2047 frames otherwise.
+    pub frames: u16,
+}
+
+/// USB 2.0 high-speed test mode, as selected by the SET_FEATURE(TEST_MODE) request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TestMode {
+    /// Test_J: drive a constant J state
+    J = 0b001,
+    /// Test_K: drive a constant K state
+    K = 0b010,
+    /// Test_SE0_NAK: stay in high-speed receive mode and NAK all IN tokens
+    Se0Nak = 0b011,
+    /// Test_Packet: repeat the standard test packet
+    Packet = 0b100,
+    /// Test_Force_Enable
+    ForceEnable = 0b101,
+}
+
+/// OUT packet borrowed in place from the endpoint buffer, see `UsbBus::borrow_packet`.
+///
+/// Dereferences to the packet data. The packet is released when the guard is dropped.
+pub struct PacketGuard<'a> {
+    endpoint: &'a EndpointOut,
+    data: *const u8,
+    len: usize,
+}
+
+impl core::ops::Deref for PacketGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // The buffer isn't written to while it is borrowed
+        unsafe { core::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for PacketGuard<'_> {
+    fn drop(&mut self) {
+        interrupt::free(|cs| self.endpoint.release_packet(cs));
+    }
+}
+
+/// Registers of one endpoint, see `UsbBus::with_endpoint_registers`.
+pub struct EndpointRegisters {
+    base: usize,
+}
+
+impl EndpointRegisters {
+    fn register(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    /// Reads the control register (DIEPCTLx or DOEPCTLx).
+    pub fn ctl(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(self.register(0x00)) }
+    }
+
+    /// Writes the control register (DIEPCTLx or DOEPCTLx).
+    pub fn set_ctl(&self, value: u32) {
+        unsafe { core::ptr::write_volatile(self.register(0x00), value) }
+    }
+
+    /// Reads the interrupt register (DIEPINTx or DOEPINTx).
+    pub fn int(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(self.register(0x08)) }
+    }
+
+    /// Clears the interrupt bits set in `bits` (DIEPINTx or DOEPINTx, write 1 to clear).
+    pub fn clear_int(&self, bits: u32) {
+        unsafe { core::ptr::write_volatile(self.register(0x08), bits) }
+    }
+
+    /// Reads the transfer size register (DIEPTSIZx or DOEPTSIZx).
+    pub fn tsiz(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(self.register(0x10)) }
+    }
+
+    /// Writes the transfer size register (DIEPTSIZx or DOEPTSIZx).
+    pub fn set_tsiz(&self, value: u32) {
+        unsafe { core::ptr::write_volatile(self.register(0x10), value) }
+    }
+}
+
+/// Link state event, see `UsbBus::next_event`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UsbEvent {
+    /// Bus reset (GINTSTS.USBRST).
+    Reset,
+    /// Enumeration done (GINTSTS.ENUMDNE) at the given speed.
+    EnumerationDone(UsbSpeed),
+    /// Suspend (GINTSTS.USBSUSP).
+    Suspend,
+    /// Resume or remote wakeup (GINTSTS.WKUPINT).
+    Resume,
+    /// The device has been disconnected by the driver, e.g. by `simulate_disconnect` or
+    /// `core_soft_reset`.
+    Disconnect,
+}
+
+/// Answer of a `SetupFilter` to a SETUP packet.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SetupAction {
+    /// Hand the request to `usb-device`.
+    Pass,
+    /// Accept a request by completing the status stage, after an empty data stage for IN
+    /// requests. OUT requests with a data stage are stalled.
+    Accept,
+    /// Answer an IN request with data, truncated to wLength. OUT requests are stalled.
+    Respond(&'static [u8]),
+    /// Reject the request by stalling EP0.
+    Stall,
+}
+
+/// Low-power state of the core, decoded from the PCGCCTL register.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LowPowerState {
+    /// The PHY clock is stopped (PCGCCTL.STPPCLK).
+    pub phy_clock_stopped: bool,
+    /// The core clock is gated (PCGCCTL.GATEHCLK).
+    pub core_clock_gated: bool,
+    /// The PHY is suspended (PCGCCTL.PHYSUSP).
+    pub phy_suspended: bool,
+    /// The PHY is in LPM sleep (PCGCCTL.PHYSLEEP). Always `false` on cores without LPM support.
+    pub phy_sleep: bool,
+}
+
+impl LowPowerState {
+    fn from_pcgcctl(pcgcctl: u32) -> Self {
+        Self {
+            phy_clock_stopped: pcgcctl & (1 << 0) != 0,
+            core_clock_gated: pcgcctl & (1 << 1) != 0,
+            phy_suspended: pcgcctl & (1 << 4) != 0,
+            phy_sleep: pcgcctl & (1 << 6) != 0,
+        }
+    }
+}
+
+/// Endpoint configuration stored in a `ConfigSnapshot`.
+///
+/// All fields are plain integers, so any bit pattern, e.g. left in retained memory by a power
+/// loss, is a valid value. `UsbBus::restore_config` rejects snapshots that don't match the bus.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct EndpointSnapshot {
+    /// Maximum packet size of the current alternate setting.
+    pub max_packet_size: u16,
+
+    /// Endpoint transfer type, as encoded in the endpoint descriptor (0 = control,
+    /// 1 = isochronous, 2 = bulk, 3 = interrupt).
+    pub ep_type: u8,
+
+    /// 1 if the endpoint is allocated, 0 otherwise.
+    pub allocated: u8,
+}
+
+impl EndpointSnapshot {
+    fn new(cs: &CriticalSection, ep: &Endpoint) -> Self {
+        EndpointSnapshot {
+            max_packet_size: ep.active_max_packet_size(cs),
+            ep_type: ep.ep_type() as u8,
+            allocated: 1,
+        }
+    }
+
+    /// Returns `true` if the snapshot can be applied to `ep`, or describes no endpoint if `ep`
+    /// is `None`
+    fn matches(&self, ep: Option<&Endpoint>) -> bool {
+        match ep {
+            None => self.allocated == 0,
+            Some(ep) => {
+                self.allocated == 1
+                    && self.ep_type == ep.ep_type() as u8
+                    && self.max_packet_size <= ep.max_packet_size()
+            }
+        }
+    }
+}
+
+/// Snapshot of the endpoint configuration and FIFO layout of a `UsbBus`.
+///
+/// See `UsbBus::save_config` and `UsbBus::restore_config`. Like `EndpointSnapshot`, it only
+/// holds plain integers and has a C layout, so it can be placed in retained memory as is.
+/// `to_bytes` and `from_bytes` convert it to and from a fixed little-endian byte format for
+/// storage that isn't tied to the struct layout.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigSnapshot {
+    /// IN endpoints, indexed by endpoint number.
+    pub endpoints_in: [EndpointSnapshot; 9],
+
+    /// OUT endpoints, indexed by endpoint number.
+    pub endpoints_out: [EndpointSnapshot; 9],
+
+    /// RX FIFO size in words.
+    pub rx_fifo_size_words: u16,
+
+    /// TX FIFO sizes in words, indexed by FIFO number.
+    pub tx_fifo_size_words: [u16; 9],
+
+    /// TX FIFO numbers, indexed by IN endpoint number.
+    pub tx_fifo_numbers: [u8; 9],
+}
+
+impl ConfigSnapshot {
+    /// Length of the byte format of a snapshot.
+    pub const SIZE: usize = 2 * 9 * 4 + 2 + 9 * 2 + 9;
+
+    /// Encodes the snapshot: the IN and OUT endpoints (packet size, type, allocated), the RX
+    /// FIFO size, the TX FIFO sizes and the TX FIFO numbers, multi-byte fields little-endian.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        let mut pos = 0;
+        let mut put = |field: &[u8]| {
+            bytes[pos..pos + field.len()].copy_from_slice(field);
+            pos += field.len();
+        };
+
+        for ep in self.endpoints_in.iter().chain(&self.endpoints_out) {
+            put(&ep.max_packet_size.to_le_bytes());
+            put(&[ep.ep_type, ep.allocated]);
+        }
+        put(&self.rx_fifo_size_words.to_le_bytes());
+        for size in &self.tx_fifo_size_words {
+            put(&size.to_le_bytes());
+        }
+        put(&self.tx_fifo_numbers);
+
+        bytes
+    }
+
+    /// Decodes a snapshot encoded by `to_bytes`.
+    ///
+    /// Every byte sequence decodes to a snapshot, the contents are only validated by
+    /// `UsbBus::restore_config`.
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut config = ConfigSnapshot::default();
+
+        let (endpoints, rest) = bytes.split_at(2 * 9 * 4);
+        let all_endpoints = config.endpoints_in.iter_mut().chain(config.endpoints_out.iter_mut());
+        for (ep, field) in all_endpoints.zip(endpoints.chunks_exact(4)) {
+            ep.max_packet_size = u16::from_le_bytes([field[0], field[1]]);
+            ep.ep_type = field[2];
+            ep.allocated = field[3];
+        }
+
+        let (rx_fifo_size, rest) = rest.split_at(2);
+        config.rx_fifo_size_words = u16::from_le_bytes([rx_fifo_size[0], rx_fifo_size[1]]);
+
+        let (tx_fifo_sizes, tx_fifo_numbers) = rest.split_at(9 * 2);
+        for (size, field) in config.tx_fifo_size_words.iter_mut().zip(tx_fifo_sizes.chunks_exact(2)) {
+            *size = u16::from_le_bytes([field[0], field[1]]);
+        }
+        config.tx_fifo_numbers.copy_from_slice(tx_fifo_numbers);
+
+        config
+    }
+}
+
+/// Status of a packet in the RX FIFO, as reported in GRXSTSR/GRXSTSP.PKTSTS.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PacketStatus {
+    /// Global OUT NAK (triggers an interrupt)
+    GlobalOutNak,
+    /// OUT data packet received
+    OutData,
+    /// OUT transfer completed (triggers an interrupt)
+    OutComplete,
+    /// SETUP transaction completed (triggers an interrupt)
+    SetupComplete,
+    /// SETUP data packet received
+    SetupData,
+    /// Reserved status value
+    Reserved(u8),
+}
+
+/// Decoded contents of the GRXSTSR/GRXSTSP register.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RxStatus {
+    /// Endpoint number the packet belongs to. Not validated against the endpoint count.
+    pub epnum: u8,
+    /// Byte count of the received data packet. Not validated against any buffer size.
+    pub byte_count: u16,
+    /// Data PID of the received OUT data packet.
+    pub dpid: u8,
+    /// Packet status.
+    pub packet_status: PacketStatus,
+    /// Frame number, only valid for isochronous OUT endpoints.
+    pub frame_number: u8,
+}
+
+/// Decodes a raw GRXSTSR/GRXSTSP value.
+///
+/// This function has no side effects and never panics, all bit patterns are accepted.
+pub fn decode_grxsts(raw: u32) -> RxStatus {
+    let packet_status = match (raw >> 17) & 0xf {
+        0x01 => PacketStatus::GlobalOutNak,
+        0x02 => PacketStatus::OutData,
+        0x03 => PacketStatus::OutComplete,
+        0x04 => PacketStatus::SetupComplete,
+        0x06 => PacketStatus::SetupData,
+        other => PacketStatus::Reserved(other as u8),
+    };
+
+    RxStatus {
+        epnum: (raw & 0xf) as u8,
+        byte_count: ((raw >> 4) & 0x7ff) as u16,
+        dpid: ((raw >> 15) & 0x3) as u8,
+        packet_status,
+        frame_number: ((raw >> 21) & 0xf) as u8,
+    }
+}
+
+/// Location of a TX FIFO in the FIFO RAM.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TxFifoLayout {
+    /// Start address in words.
+    pub start_address_words: u16,
+
+    /// Depth in words.
+    pub depth_words: u16,
+}
+
+/// FIFO layout as programmed into GRXFSIZ and DIEPTXFx.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FifoLayout {
+    /// RX FIFO size in words. The RX FIFO always starts at address 0.
+    pub rx_fifo_size_words: u16,
+
+    /// TX FIFOs, indexed by FIFO number.
+    pub tx_fifos: [TxFifoLayout; 9],
+}
+
+/// Precomputed FIFO sizes, see `UsbBus::set_fifo_profile`.
+///
+/// The FIFOs are laid out back to back as described by `FifoLayout`, so only their sizes are
+/// needed: the RX FIFO starts at address 0, followed by TX FIFO 0 and the other TX FIFOs in
+/// order.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FifoProfile {
+    /// RX FIFO size in words.
+    pub rx_fifo_size_words: u16,
+
+    /// TX FIFO sizes in words, indexed by FIFO number.
+    pub tx_fifo_sizes_words: [u16; 9],
+}
+
+impl FifoProfile {
+    /// Computes the sizes the driver would use for a set of endpoints.
+    ///
+    /// `endpoints` has to list all endpoints, including EP0 in both directions. Each IN
+    /// endpoint is assumed to use the TX FIFO with its own number. Double-buffered OUT endpoints
+    /// (`UsbPeripheral::double_buffered_out`) need twice their buffer size in the RX FIFO, which
+    /// isn't included here. The maximum packet size doesn't depend on the transfer type, so
+    /// `ep_type` is ignored.
+    pub fn compute(endpoints: &[ExpectedEndpoint]) -> Self {
+        let mut profile = FifoProfile::default();
+
+        let mut rx_buffer_words = 0;
+        for endpoint in endpoints {
+            let index = endpoint.address.index();
+            let size_words = (endpoint.max_packet_size as usize).div_ceil(4);
+            if endpoint.address.is_out() {
+                rx_buffer_words += size_words;
+            } else if let Some(fifo_size) = profile.tx_fifo_sizes_words.get_mut(index) {
+                // TX FIFOs are at least 16 words, like the ones allocated by the driver
+                *fifo_size = core::cmp::max(size_words, 16) as u16;
+            }
+        }
+        profile.rx_fifo_size_words = (rx_buffer_words + RX_FIFO_HEADROOM_WORDS) as u16;
+
+        profile
+    }
+}
+
+/// USB bus speed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UsbSpeed {
+    /// High speed (480 Mbit/s)
+    High,
+    /// Full speed (12 Mbit/s)
+    Full,
+    /// Low speed (1.5 Mbit/s)
+    Low,
+}
+
+impl UsbSpeed {
+    /// Decodes the DSTS.ENUMSPD field.
+    fn from_enumspd(enumspd: u32) -> Self {
+        match enumspd {
+            0b00 => UsbSpeed::High,
+            0b01 | 0b11 => UsbSpeed::Full,
+            _ => UsbSpeed::Low,
+        }
+    }
+
+    /// Mask of the DSTS.FNSOF bits that count at this speed. FNSOF counts microframes at high
+    /// speed and wraps after 2048 frames otherwise.
+    fn frame_number_mask(self) -> u16 {
+        match self {
+            UsbSpeed::High => 0x3fff,
+            UsbSpeed::Full | UsbSpeed::Low => 0x7ff,
+        }
+    }
+}
+
+/// Device status, decoded from the DSTS register and the soft disconnect bit of DCTL.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeviceStatus {
+    /// The bus is in the suspend state (DSTS.SUSPSTS).
+    pub suspended: bool,
+    /// Speed enumerated by the last bus reset (DSTS.ENUMSPD). Only meaningful after the
+    /// enumeration has completed.
+    pub enumerated_speed: UsbSpeed,
+    /// An erratic error has been detected on the bus (DSTS.EERR).
+    pub erratic_error: bool,
+    /// The device is soft-disconnected (DCTL.SDIS).
+    pub soft_disconnected: bool,
+    /// Frame number of the last received SOF (DSTS.FNSOF).
+    pub frame_number: u16,
+}
+
+/// Power source of the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PowerSource {
+    /// The device draws its power from VBUS.
+    BusPowered,
+    /// The device has its own power supply.
+    SelfPowered,
+}
+
+/// Device state tracked by the driver.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceState {
+    /// The device has been reset and has no address assigned.
+    Default,
+    /// The device has been assigned an address.
+    Address,
+    /// The device has been configured by the host.
+    Configured,
+}
+
+/// Counts (micro)frames without endpoint activity
+#[derive(Copy, Clone, Default)]
+struct IdleTracker {
+    last_frame: u16,
+    idle_frames: u32,
+}
+
+impl IdleTracker {
+    fn update(&mut self, frame_number: u16, frame_mask: u16, activity: bool) {
+        let elapsed = frame_number.wrapping_sub(self.last_frame) & frame_mask;
+        self.last_frame = frame_number;
+
+        if activity {
+            self.idle_frames = 0;
+        } else {
+            self.idle_frames = self.idle_frames.saturating_add(elapsed as u32);
+        }
+    }
+}
+
+/// Counts periodic checks without a new SOF frame number
+#[derive(Copy, Clone, Default)]
+struct HostSilenceMonitor {
+    limit: Option<u32>,
+    last_frame: Option<u16>,
+    silent_checks: u32,
+}
+
+impl HostSilenceMonitor {
+    fn update(&mut self, frame_number: u16, monitored: bool) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+
+        if monitored && self.last_frame == Some(frame_number) {
+            self.silent_checks = self.silent_checks.saturating_add(1);
+        } else {
+            self.silent_checks = 0;
+        }
+        self.last_frame = monitored.then_some(frame_number);
+
+        self.silent_checks >= limit
+    }
+}
+
+/// Counts (micro)frames between the first bus reset and the last enumeration
+#[derive(Copy, Clone, Default)]
+struct EnumerationTimer {
+    reset_seen: bool,
+    start_frame: Option<u16>,
+    frames: Option<u16>,
+}
+
+/// Number of link state events kept by the event queue, see `UsbBus::set_event_queue_enabled`.
+const EVENT_QUEUE_LEN: usize = 8;
+
+/// Ring buffer of link state events
+#[derive(Copy, Clone, Default)]
+struct EventQueue {
+    enabled: bool,
+    events: [Option<UsbEvent>; EVENT_QUEUE_LEN],
+    head: usize,
+    len: usize,
+    overflow: bool,
+}
+
+impl EventQueue {
+    fn push(&mut self, event: UsbEvent) {
+        if !self.enabled {
+            return;
+        }
+        if self.len == EVENT_QUEUE_LEN {
+            self.overflow = true;
+            return;
+        }
+        self.events[(self.head + self.len) % EVENT_QUEUE_LEN] = Some(event);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<UsbEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % EVENT_QUEUE_LEN;
+        self.len -= 1;
+        event
+    }
+}
+
+/// Counts the polls that saw NAKed OUT tokens per OUT endpoint since the last accepted packet
+#[derive(Copy, Clone, Default)]
+struct OutNakMonitor {
+    limit: Option<u32>,
+    counts: [u32; 9],
+    exceeded: u16,
+}
+
+/// Stage of a control transfer claimed by the SETUP filter
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum FilteredStage {
+    None,
+    DataInZlp,
+    StatusIn,
+    StatusOut,
+}
+
+/// Counts bus resets within the window of the reset rate limit
+#[derive(Copy, Clone, Default)]
+struct ResetRateTracker {
+    limit: Option<ResetRateLimit>,
+    window_start: u16,
+    resets: u16,
+    storm: bool,
+}
+
+/// Counts SOFs between a bus reset and the enumeration that should follow it
+#[derive(Copy, Clone, Default)]
+struct EnumerationWatchdog {
+    limit: Option<u16>,
+    frames: Option<u16>,
+    stuck: bool,
+}
+
+/// Returns `true` for F429-like cores (CID 0x1100 and 0x1200), which need OUT endpoints to be
+/// re-enabled when a transfer or SETUP stage completes
+fn is_f429_like_core(core_id: u32) -> bool {
+    matches!(core_id, 0x0000_1100 | 0x0000_1200)
+}
+
+/// Returns `true` for F446-like cores (CID 0x2000 to 0x3100), which need OUT endpoints to be
+/// re-enabled after each data packet and support LPM
+fn is_f446_like_core(core_id: u32) -> bool {
+    matches!(core_id, 0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100)
+}
+
+/// Returns `true` if a control request has an IN data stage (bmRequestType bit 7)
+fn has_data_in(request_type: u8) -> bool {
+    request_type & 0x80 != 0
+}
 
-            // Begin ULPI register write transaction
-            modify_reg!(otg_global, regs.global(), PHYCR,
-                NEW: 1,
-                RW: 1,
-                ADDR: addr as u32,
-                DATA: data as u32
-            );
+/// Returns `true` if a zero-length packet on EP0 in `direction` completes the status stage of
+/// the current control transfer, see `has_data_in`
+///
+/// The status stage runs opposite to the data stage. Requests without a data stage are
+/// host-to-device, so their status stage is a ZLP sent by the device.
+fn is_status_stage(data_in: bool, direction: UsbDirection) -> bool {
+    match direction {
+        UsbDirection::In => !data_in,
+        UsbDirection::Out => data_in,
+    }
+}
 
-            // ULPI transactions should take less than 1us. 1000 iterations should be enough even for fast MCUs.
-            let mut timeout = 1000;
-            while timeout > 0 {
-                // Wait for transaction to complete
-                if read_reg!(otg_global, regs.global(), PHYCR, DONE) != 0 {
-                    return Ok(());
-                }
-                timeout -= 1;
-            }
-            Err(UlpiError::Timeout)
-        })
+/// Computes TX FIFO sizes in words, indexed by FIFO number
+///
+/// `endpoint_sizes` holds the TX buffer size of each IN endpoint, 0 for endpoints that aren't
+/// allocated. A FIFO shared by several IN endpoints is sized for the largest of them, FIFOs that
+/// have no endpoints mapped to them are left empty. Non-zero `overrides` replace the computed
+/// sizes.
+fn tx_fifo_sizes_for(endpoint_sizes: &[u16; 9], tx_fifo_map: &[u8; 9], overrides: &[u16; 9]) -> [u16; 9] {
+    let mut tx_fifo_sizes = [0; 9];
+    for (&size, &fifo) in endpoint_sizes.iter().zip(tx_fifo_map.iter()) {
+        let fifo_size = &mut tx_fifo_sizes[fifo as usize];
+        *fifo_size = core::cmp::max(*fifo_size, size);
     }
+    for (fifo_size, &size) in tx_fifo_sizes.iter_mut().zip(overrides.iter()) {
+        if size != 0 {
+            *fifo_size = size;
+        }
+    }
+    tx_fifo_sizes
 }
 
-#[cfg(feature = "hs")]
-#[derive(Debug)]
-/// Errors that can occur while interfacing with a ULPI PHY.
-pub enum UlpiError {
-    /// The action has timed out.
-    Timeout,
+/// Compile-time check that `WORDS` of endpoint memory fit into the peripheral FIFO
+struct EndpointMemoryFits<USB, const WORDS: usize>(PhantomData<USB>);
+
+impl<USB: UsbPeripheral, const WORDS: usize> EndpointMemoryFits<USB, WORDS> {
+    const OK: () = assert!(WORDS <= USB::FIFO_DEPTH_WORDS, "endpoint memory is larger than the FIFO");
 }
 
 pub(crate) struct EndpointAllocator<USB> {
@@ -236,12 +4464,15 @@ pub(crate) struct EndpointAllocator<USB> {
     endpoints_in: [Option<EndpointIn>; 9],
     endpoints_out: [Option<EndpointOut>; 9],
     memory_allocator: EndpointMemoryAllocator<USB>,
+    overflow: Option<FifoOverflow>,
     _marker: PhantomData<USB>,
 }
 
 impl<USB: UsbPeripheral> EndpointAllocator<USB> {
+    const ENDPOINT_COUNT_OK: () = assert!(USB::ENDPOINT_COUNT <= 9, "at most 9 endpoints are supported");
+
     fn new(memory: &'static mut [u32]) -> Self {
-        assert!(USB::ENDPOINT_COUNT <= 9);
+        let () = Self::ENDPOINT_COUNT_OK;
         Self {
             bitmap_in: 0,
             bitmap_out: 0,
@@ -249,6 +4480,7 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
             endpoints_in: [None, None, None, None, None, None, None, None, None],
             endpoints_out: [None, None, None, None, None, None, None, None, None],
             memory_allocator: EndpointMemoryAllocator::new(memory),
+            overflow: None,
             _marker: PhantomData
         }
     }
@@ -276,6 +4508,10 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         }
     }
 
+    fn free_number(bitmap: &mut u8, number: u8) {
+        *bitmap &= !(1 << number);
+    }
+
     fn alloc(bitmap: &mut u8, config: &EndpointConfig, direction: UsbDirection) -> Result<EndpointDescriptor> {
         let number = Self::alloc_number(bitmap, config.number)?;
         let address = EndpointAddress::from_parts(number as usize, direction);
@@ -287,26 +4523,67 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         })
     }
 
+    fn record_overflow(&mut self, address: EndpointAddress) {
+        if let Some((requested_words, available_words)) = self.memory_allocator.take_overflow() {
+            self.overflow = Some(FifoOverflow {
+                endpoint: Some(address),
+                requested_words,
+                available_words,
+            });
+        }
+    }
+
     fn alloc_in(&mut self, config: &EndpointConfig) -> Result<EndpointIn> {
         let descr = Self::alloc(&mut self.bitmap_in, config, UsbDirection::In)?;
 
-        self.memory_allocator.allocate_tx_buffer(descr.address.index() as u8, descr.max_packet_size as usize)?;
+        let number = descr.address.index() as u8;
+        if let Err(err) = self.memory_allocator.allocate_tx_buffer(number, descr.max_packet_size as usize) {
+            // Don't leak the endpoint number if there is no memory for it
+            Self::free_number(&mut self.bitmap_in, number);
+            self.record_overflow(descr.address);
+            return Err(err);
+        }
         let ep = EndpointIn::new::<USB>(descr);
 
         Ok(ep)
     }
 
-    fn alloc_out(&mut self, config: &EndpointConfig) -> Result<EndpointOut> {
+    fn alloc_out(&mut self, peripheral: &USB, config: &EndpointConfig) -> Result<EndpointOut> {
         let descr = Self::alloc(&mut self.bitmap_out, config, UsbDirection::Out)?;
 
-        let buffer = self.memory_allocator.allocate_rx_buffer(descr.max_packet_size as usize)?;
-        let ep = EndpointOut::new::<USB>(descr, buffer);
+        // The spare buffer may fail after the first one succeeded, both are given back then
+        let mark = self.memory_allocator.mark();
+        let buffer = match self.memory_allocator.allocate_rx_buffer(descr.max_packet_size as usize) {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                // Don't leak the endpoint number if there is no memory for it
+                Self::free_number(&mut self.bitmap_out, descr.address.index() as u8);
+                self.record_overflow(descr.address);
+                return Err(err);
+            }
+        };
+        let spare = if descr.address.index() != 0 && peripheral.double_buffered_out(descr.address) {
+            match self.memory_allocator.allocate_rx_buffer(descr.max_packet_size as usize) {
+                Ok(spare) => Some(spare),
+                Err(err) => {
+                    // `buffer` is dropped on return, its memory can be reused
+                    self.memory_allocator.restore(mark);
+                    Self::free_number(&mut self.bitmap_out, descr.address.index() as u8);
+                    self.record_overflow(descr.address);
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+        let ep = EndpointOut::new::<USB>(descr, buffer, spare);
 
         Ok(ep)
     }
 
     fn alloc_ep(
         &mut self,
+        peripheral: &USB,
         ep_dir: UsbDirection,
         ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
@@ -316,6 +4593,15 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         let ep_type = unsafe { core::mem::transmute(ep_type) };
         let number = ep_addr.map(|a| a.index() as u8);
 
+        if number == Some(0) && !matches!(max_packet_size, 8 | 16 | 32 | 64) {
+            return Err(UsbError::Unsupported);
+        }
+        // EP0 must be 64 bytes at high speed, and the device descriptor reports the allocated size
+        let high_speed_phy = USB::HIGH_SPEED && peripheral.phy_type() != PhyType::InternalFullSpeed;
+        if number == Some(0) && high_speed_phy && max_packet_size != 64 {
+            return Err(UsbError::Unsupported);
+        }
+
         let config = EndpointConfig {
             ep_type,
             max_packet_size,
@@ -325,7 +4611,7 @@ impl<USB: UsbPeripheral> EndpointAllocator<USB> {
         };
         match ep_dir {
             UsbDirection::Out => {
-                let ep = self.alloc_out(&config)?;
+                let ep = self.alloc_out(peripheral, &config)?;
                 let address = ep.address();
                 self.endpoints_out[address.index()] = Some(ep);
                 Ok(address)
@@ -349,7 +4635,11 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         max_packet_size: u16,
         interval: u8) -> Result<EndpointAddress>
     {
-        self.allocator.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size, interval)
+        let result = self.allocator.alloc_ep(&self.peripheral, ep_dir, ep_addr, ep_type, max_packet_size, interval);
+        if let Some(report) = self.allocator.overflow.take() {
+            self.peripheral.fifo_overflow(report);
+        }
+        result
     }
 
     fn enable(&mut self) {
@@ -358,150 +4648,28 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
 
         interrupt::free(|cs| {
             let regs = self.regs.borrow(cs);
-
-            let core_id = read_reg!(otg_global, regs.global(), CID);
-
-            // Wait for AHB ready
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-
-            // Configure OTG as device
-            #[cfg(feature = "fs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                FDMOD: 1 // Force device mode
-            );
-            #[cfg(feature = "hs")]
-            modify_reg!(otg_global, regs.global(), GUSBCFG,
-                SRPCAP: 0, // SRP capability is not enabled
-                TOCAL: 0x1,
-                FDMOD: 1 // Force device mode
-            );
-
-            // Configure USB PHY
-            #[cfg(feature = "hs")]
-            match self.peripheral.phy_type() {
-                PhyType::InternalFullSpeed => {
-                    // Select FS Embedded PHY
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, PHYSEL: 1);
-                },
-                PhyType::InternalHighSpeed => {
-                    // Turn off PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The UTMI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
-
-                    // Select UTMI Interace
-                    //modify_reg!(otg_global, regs.global(), GUSBCFG, ULPISEL: 0);
-                    modify_reg!(otg_global, regs.global(), GUSBCFG, |r| r & !(1 << 4));
-
-                    // This is a secret bit from ST that is not mentioned anywhere except
-                    // the driver code shipped with STM32CubeIDE.
-                    //modify_reg!(otg_global, regs.global(), GCCFG, PHYHSEN: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 23));
-
-                    self.peripheral.setup_internal_hs_phy();
-                }
-                PhyType::ExternalHighSpeed => {
-                    // Turn off embedded PHY
-                    modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 0);
-
-                    // Init The ULPI Interface
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        TSDPS: 0,
-                        ULPIFSLS: 0,
-                        PHYSEL: 0 // ULPI or UTMI
-                    );
-
-                    // Select VBUS source
-                    modify_reg!(otg_global, regs.global(), GUSBCFG,
-                        ULPIEVBUSD: 0,
-                        ULPIEVBUSI: 0
-                    );
+            // Some vendors report their own ID here, so a mismatch is only reported
+            let synopsys_id = regs.gsnpsid().read();
+            if synopsys_id & 0xffff_0000 != 0x4f54_0000 {
+                self.peripheral.core_mismatch(CoreMismatch::UnknownId(synopsys_id));
+            }
+            if USB::HIGH_SPEED {
+                // GHWCFG2.HSPHYTYPE is zero on cores without high-speed support
+                let hs_phy_type = (regs.ghwcfg2().read() >> 6) & 0b11;
+                if hs_phy_type == 0 {
+                    self.peripheral.core_mismatch(CoreMismatch::NoHighSpeedPhy);
                 }
             }
 
-            // Perform core soft-reset
-            while read_reg!(otg_global, regs.global(), GRSTCTL, AHBIDL) == 0 {}
-            modify_reg!(otg_global, regs.global(), GRSTCTL, CSRST: 1);
-            while read_reg!(otg_global, regs.global(), GRSTCTL, CSRST) == 1 {}
-
-            if self.peripheral.phy_type() == PhyType::InternalFullSpeed {
-                // Activate the USB Transceiver
-                modify_reg!(otg_global, regs.global(), GCCFG, PWRDWN: 1);
+            // An MCU reset also resets the core, so only the peripheral can tell whether the
+            // host may still consider the device enumerated
+            if self.peripheral.warm_reset() {
+                self.warm_reset_pending.borrow(cs).set(true);
             }
 
-            // Configuring Vbus sense and SOF output
-            match core_id {
-                0x0000_1200 | 0x0000_1100 => {
-                    // F429-like chips have the GCCFG.NOVBUSSENS bit
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, NOVBUSSENS: 1);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r | (1 << 21));
-
-                    modify_reg!(otg_global, regs.global(), GCCFG, VBUSASEN: 0, VBUSBSEN: 0, SOFOUTEN: 0);
-                }
-                0x0000_2000 | 0x0000_2100 | 0x0000_2300 | 0x0000_3000 | 0x0000_3100 => {
-                    // F446-like chips have the GCCFG.VBDEN bit with the opposite meaning
-
-                    //modify_reg!(otg_global, regs.global, GCCFG, VBDEN: 0);
-                    modify_reg!(otg_global, regs.global(), GCCFG, |r| r & !(1 << 21));
-
-                    // Force B-peripheral session
-                    //modify_reg!(otg_global, regs.global, GOTGCTL, BVALOEN: 1, BVALOVAL: 1);
-                    modify_reg!(otg_global, regs.global(), GOTGCTL, |r| r | (0b11 << 6));
-                }
-                _ => {}
+            if !self.init_core(cs) {
+                fatal!("USB core doesn't respond");
             }
-
-            // Enable PHY clock
-            write_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, 0);
-
-            // Soft disconnect device
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
-
-            // Setup USB speed and frame interval
-            let speed = match (USB::HIGH_SPEED, self.peripheral.phy_type()) {
-                (false, _) => 0b11,
-                (true, PhyType::InternalFullSpeed) => 0b11,
-                (true, PhyType::InternalHighSpeed) => 0b00,
-                (true, PhyType::ExternalHighSpeed) => 0b00,
-            };
-            modify_reg!(otg_device, regs.device(), DCFG,
-                PFIVL: 0b00,
-                DSPD: speed
-            );
-            #[cfg(feature = "xcvrdly")]
-            modify_reg!(otg_device, regs.device(), DCFG, XCVRDLY: 1);
-
-            // unmask EP interrupts
-            write_reg!(otg_device, regs.device(), DIEPMSK, XFRCM: 1);
-
-            // unmask core interrupts
-            write_reg!(otg_global, regs.global(), GINTMSK,
-                USBRST: 1, ENUMDNEM: 1,
-                USBSUSPM: 1, WUIM: 1,
-                IEPINT: 1, RXFLVLM: 1
-            );
-
-            // clear pending interrupts
-            write_reg!(otg_global, regs.global(), GINTSTS, 0xffffffff);
-
-            // unmask global interrupt
-            modify_reg!(otg_global, regs.global(), GAHBCFG, GINT: 1);
-
-            // connect(true)
-            modify_reg!(otg_device, regs.device(), DCTL, SDIS: 0);
         });
     }
 
@@ -509,9 +4677,14 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
         interrupt::free(|cs| {
             let regs = self.regs.borrow(cs);
 
-            self.configure_all(cs);
+            // `poll` may have done this already, see `set_configure_on_enumeration`
+            if !self.configured_at_enumeration.borrow(cs).replace(false) {
+                self.configure_all(cs);
+            }
 
             modify_reg!(otg_device, regs.device(), DCFG, DAD: 0);
+
+            self.device_state.borrow(cs).set(DeviceState::Default);
         });
     }
 
@@ -520,6 +4693,9 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
             let regs = self.regs.borrow(cs);
 
             modify_reg!(otg_device, regs.device(), DCFG, DAD: addr as u32);
+
+            let state = if addr == 0 { DeviceState::Default } else { DeviceState::Address };
+            self.device_state.borrow(cs).set(state);
         });
     }
 
@@ -528,22 +4704,31 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
             return Err(UsbError::InvalidEndpoint);
         }
         if let Some(ep) = &self.allocator.endpoints_in[ep_addr.index()] {
-            ep.write(buf).map(|_| buf.len())
+            #[cfg(feature = "strict")]
+            interrupt::free(|cs| self.check_endpoint_access(cs, ep_addr, ep.state(cs)))?;
+
+            let result = ep.write(buf);
+            if let Err(UsbError::WouldBlock) = result {
+                interrupt::free(|cs| self.wait_for_tx_fifo(cs, ep_addr.index()));
+            }
+            result?;
+
+            if ep_addr.index() == 0 {
+                interrupt::free(|cs| self.ep0_in_zlp.borrow(cs).set(buf.is_empty()));
+            }
+            self.record_activity();
+
+            #[cfg(feature = "throughput")]
+            self.record_throughput(ep_addr, buf.len());
+
+            Ok(buf.len())
         } else {
             Err(UsbError::InvalidEndpoint)
         }
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
-        if !ep_addr.is_out() || ep_addr.index() >= USB::ENDPOINT_COUNT {
-            return Err(UsbError::InvalidEndpoint);
-        }
-
-        if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
-            ep.read(buf)
-        } else {
-            Err(UsbError::InvalidEndpoint)
-        }
+        self.read_out(ep_addr, buf, false)
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
@@ -551,95 +4736,233 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
             return;
         }
 
+        // Leave the registers of endpoints that don't exist alone
+        #[cfg(feature = "strict")]
+        if self.endpoint_halted(ep_addr).is_err() {
+            return;
+        }
+
         let regs = UsbRegisters::new::<USB>();
-        crate::endpoint::set_stalled(regs, ep_addr, stalled)
-    }
+        let was_stalled = crate::endpoint::is_stalled(regs, ep_addr);
+        crate::endpoint::set_stalled(regs, ep_addr, stalled);
+        #[cfg(feature = "trace")]
+        interrupt::free(|cs| self.trace(cs, TraceEvent::Stall(ep_addr, stalled)));
 
-    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
-        if ep_addr.index() >= USB::ENDPOINT_COUNT {
-            return true;
+        // A stalled control transfer is over
+        if ep_addr.index() == 0 && stalled {
+            interrupt::free(|cs| self.control_transfer_active.borrow(cs).set(false));
         }
 
-        let regs = UsbRegisters::new::<USB>();
-        crate::endpoint::is_stalled(regs, ep_addr)
+        // Clearing STALL alone leaves an OUT endpoint silent, it has to be re-armed
+        if was_stalled && !stalled && ep_addr.is_out() {
+            if let Some(ep) = &self.allocator.endpoints_out[ep_addr.index()] {
+                interrupt::free(|cs| ep.rearm(cs));
+            }
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        // Endpoints that don't exist can't be halted
+        self.endpoint_halted(ep_addr).unwrap_or(false)
     }
 
     fn suspend(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            self.suspended.borrow(cs).set(true);
+            self.resume_frame.borrow(cs).set(None);
+
+            if self.power_source.borrow(cs).get() == PowerSource::BusPowered {
+                // Gating is deferred by `poll` until received packets have been drained
+                self.clock_gating_pending.borrow(cs).set(true);
+                self.gate_clocks_if_idle(cs);
+            }
+        });
     }
 
     fn resume(&self) {
-        // Nothing to do here?
+        interrupt::free(|cs| {
+            self.suspended.borrow(cs).set(false);
+            self.clock_gating_pending.borrow(cs).set(false);
+
+            let regs = self.regs.borrow(cs);
+            Self::ungate_clocks(regs);
+        });
     }
 
     fn poll(&self) -> PollResult {
-        interrupt::free(|cs| {
+        let pending = interrupt::free(|cs| {
+            self.poll_handled.borrow(cs).set(false);
+
+            // The core registers can't be read while the core clock is gated. The clocks are
+            // gated again at the end of `poll`, unless the bus resumed or has been reset.
+            let regs = self.regs.borrow(cs);
+            if read_reg!(otg_pwrclk, regs.pwrclk(), PCGCCTL, GATEHCLK) != 0 {
+                Self::ungate_clocks(regs);
+                self.clock_gating_pending.borrow(cs).set(true);
+            }
+
+            self.pending_interrupts(cs)
+        });
+
+        let result = interrupt::free(|cs| {
             let regs = self.regs.borrow(cs);
 
             let core_id = read_reg!(otg_global, regs.global(), CID);
 
-            let (wakeup, suspend, enum_done, reset, iep, rxflvl) = read_reg!(otg_global, regs.global(), GINTSTS,
-                WKUPINT, USBSUSP, ENUMDNE, USBRST, IEPINT, RXFLVL
+            self.update_idle(cs, false);
+
+            let (wakeup, suspend, enum_done, reset, iep, oep, rxflvl, incomplete_iso_in, sof) = read_reg!(otg_global, regs.global(), GINTSTS,
+                WKUPINT, USBSUSP, ENUMDNE, USBRST, IEPINT, OEPINT, RXFLVL, IISOIXFR, SOF
             );
+            //let incomplete_iso_out = read_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT);
+            let incomplete_iso_out = (read_reg!(otg_global, regs.global(), GINTSTS) >> 21) & 1;
+
+            if incomplete_iso_in != 0 {
+                write_reg!(otg_global, regs.global(), GINTSTS, IISOIXFR: 1);
+
+                let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF);
+                for ep in self.allocator.endpoints_in.iter().flatten() {
+                    if !ep.abort_incomplete_iso(cs, frame_number) {
+                        self.set_poll_error(cs);
+                        return PollResult::None;
+                    }
+                }
+            }
+
+            if incomplete_iso_out != 0 {
+                //write_reg!(otg_global, regs.global(), GINTSTS, IPXFR_INCOMPISOOUT: 1);
+                write_reg!(otg_global, regs.global(), GINTSTS, 1 << 21);
+
+                let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF);
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    ep.rearm_incomplete_iso(cs, frame_number);
+                }
+            }
 
             if reset != 0 {
                 write_reg!(otg_global, regs.global(), GINTSTS, USBRST: 1);
+                #[cfg(feature = "trace")]
+                self.trace(cs, TraceEvent::Reset);
+                self.queue_event(cs, UsbEvent::Reset);
+
+                if !self.tear_down(cs) {
+                    // USBRST has already been cleared, the stack still has to see the reset
+                    self.set_poll_error(cs);
+                    return PollResult::Reset;
+                }
+
+                if self.count_reset(cs) {
+                    // Back off until the application reconnects
+                    modify_reg!(otg_device, regs.device(), DCTL, SDIS: 1);
+                }
 
-                self.deconfigure_all(cs);
+                let timer = self.enumeration.borrow(cs);
+                if !timer.get().reset_seen {
+                    timer.set(EnumerationTimer { reset_seen: true, ..timer.get() });
 
-                // Flush RX
-                modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1);
-                while read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH) == 1 {}
+                    // Catch the first SOF after the reset to start counting frames
+                    write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+                    self.update_sof_mask(cs);
+                }
+
+                let watchdog = self.enumeration_watchdog.borrow(cs);
+                if watchdog.get().limit.is_some() {
+                    watchdog.set(EnumerationWatchdog { frames: Some(0), ..watchdog.get() });
+                    write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+                    self.update_sof_mask(cs);
+                }
+            }
+
+            let timer = self.enumeration.borrow(cs);
+            if sof != 0 && timer.get().reset_seen && timer.get().start_frame.is_none() {
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+
+                let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+                timer.set(EnumerationTimer { start_frame: Some(frame_number), ..timer.get() });
+                self.update_sof_mask(cs);
+            }
+
+            let watchdog = self.enumeration_watchdog.borrow(cs);
+            if let (true, Some(frames), Some(limit)) = (sof != 0, watchdog.get().frames, watchdog.get().limit) {
+                write_reg!(otg_global, regs.global(), GINTSTS, SOF: 1);
+
+                let frames = frames.saturating_add(1);
+                if frames < limit {
+                    watchdog.set(EnumerationWatchdog { frames: Some(frames), ..watchdog.get() });
+                } else {
+                    // The reset never completed, drop whatever the core may be stuck on
+                    watchdog.set(EnumerationWatchdog { frames: None, stuck: true, ..watchdog.get() });
+                    self.update_sof_mask(cs);
+
+                    modify_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH: 1, TXFFLSH: 1, TXFNUM: 0x10);
+                    #[cfg(feature = "trace")]
+                    self.trace_fifo_flushes(cs);
+                    if !spin_until(|| read_reg!(otg_global, regs.global(), GRSTCTL, RXFFLSH, TXFFLSH) == (0, 0)) {
+                        self.set_poll_error(cs);
+                        return PollResult::None;
+                    }
+                }
+            }
+
+            if self.disconnect_pending.borrow(cs).replace(false) {
+                self.queue_event(cs, UsbEvent::Disconnect);
+                return PollResult::Reset;
             }
 
             if enum_done != 0 {
                 write_reg!(otg_global, regs.global(), GINTSTS, ENUMDNE: 1);
 
                 let speed = read_reg!(otg_device, regs.device(), DSTS, ENUMSPD);
+                self.speed.borrow(cs).set(Some(UsbSpeed::from_enumspd(speed)));
+                // Set up for high speed, but enumerated at a lower speed
+                self.hs_negotiation_failed.borrow(cs).set(self.device_speed(cs) == 0b00 && speed != 0b00);
+                #[cfg(feature = "trace")]
+                self.trace(cs, TraceEvent::EnumerationDone(UsbSpeed::from_enumspd(speed)));
+                self.queue_event(cs, UsbEvent::EnumerationDone(UsbSpeed::from_enumspd(speed)));
 
-                // Compute and update TRDT
-                let trdt;
-                match speed {
-                    0b00 => {
-                        // High speed
-
-                        // From RM0431 (F72xx), RM0090 (F429), RM0390 (F446)
-                        if self.peripheral.ahb_frequency_hz() >= 30_000_000 {
-                            trdt = 0x9;
-                        } else {
-                            panic!("AHB frequency is too low")
-                        }
-                    }
-                    0b01 | 0b11 => {
-                        // Full speed
-
-                        // From RM0431 (F72xx), RM0090 (F429)
-                        trdt = match self.peripheral.ahb_frequency_hz() {
-                            0..=14_199_999 => panic!("AHB frequency is too low"),
-                            14_200_000..=14_999_999 => 0xF,
-                            15_000_000..=15_999_999 => 0xE,
-                            16_000_000..=17_199_999 => 0xD,
-                            17_200_000..=18_499_999 => 0xC,
-                            18_500_000..=19_999_999 => 0xB,
-                            20_000_000..=21_799_999 => 0xA,
-                            21_800_000..=23_999_999 => 0x9,
-                            24_000_000..=27_499_999 => 0x8,
-                            27_500_000..=31_999_999 => 0x7, // 27.7..32 in code from CubeIDE
-                            32_000_000..=u32::MAX => 0x6,
-                        };
-                    }
-                    _ => unimplemented!()
+                // DSTS.FNSOF still holds the last frame before the reset
+                let frame_number = read_reg!(otg_device, regs.device(), DSTS, FNSOF) as u16;
+                let frame_mask = UsbSpeed::from_enumspd(speed).frame_number_mask();
+                let mut enumeration = timer.get();
+                enumeration.frames = Some(enumeration.start_frame
+                    .map_or(0, |start| frame_number.wrapping_sub(start) & frame_mask));
+                timer.set(enumeration);
+
+                let watchdog = self.enumeration_watchdog.borrow(cs);
+                if watchdog.get().frames.is_some() {
+                    watchdog.set(EnumerationWatchdog { frames: None, ..watchdog.get() });
+                    self.update_sof_mask(cs);
                 }
+
+                // Compute and update TRDT
+                let trdt = self.turnaround_time(UsbSpeed::from_enumspd(speed), self.ahb_frequency_hz(cs));
                 modify_reg!(otg_global, regs.global(), GUSBCFG, TRDT: trdt);
 
+                if self.configure_on_enumeration.borrow(cs).get() {
+                    self.configure_all(cs);
+                    self.configured_at_enumeration.borrow(cs).set(true);
+                }
+
                 PollResult::Reset
             } else if wakeup != 0 {
+                // Restore the clocks before doing anything else
+                self.clock_gating_pending.borrow(cs).set(false);
+                Self::ungate_clocks(regs);
+                #[cfg(feature = "trace")]
+                self.trace(cs, TraceEvent::Wakeup);
+                self.queue_event(cs, UsbEvent::Resume);
+
                 // Clear the interrupt
                 write_reg!(otg_global, regs.global(), GINTSTS, WKUPINT: 1);
 
+                self.start_resume(cs);
+
                 PollResult::Resume
             } else if suspend != 0 {
                 write_reg!(otg_global, regs.global(), GINTSTS, USBSUSP: 1);
+                #[cfg(feature = "trace")]
+                self.trace(cs, TraceEvent::Suspend);
+                self.queue_event(cs, UsbEvent::Suspend);
 
                 PollResult::Suspend
             } else {
@@ -647,70 +4970,96 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
                 let mut ep_in_complete = 0;
                 let mut ep_setup = 0;
 
-                use crate::ral::{endpoint_in, endpoint_out};
+                let drain_fully = self.drain_fully.borrow(cs).get();
 
                 // RXFLVL & IEPINT flags are read-only, there is no need to clear them
-                if rxflvl != 0 {
-                    let (epnum, data_size, status) = read_reg!(otg_global, regs.global(), GRXSTSR, EPNUM, BCNT, PKTSTS);
-                    match status {
-                        0x02 => { // OUT received
-                            ep_out |= 1 << epnum;
-                        }
-                        0x06 => { // SETUP received
-                            // flushing TX if something stuck in control endpoint
-                            let ep = regs.endpoint_in(epnum as usize);
-                            if read_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT) != 0 {
-                                modify_reg!(otg_global, regs.global(), GRSTCTL, TXFNUM: epnum, TXFFLSH: 1);
-                                while read_reg!(otg_global, regs.global(), GRSTCTL, TXFFLSH) == 1 {}
-                            }
-                            ep_setup |= 1 << epnum;
-                        }
-                        0x03 | 0x04 => { // OUT completed | SETUP completed
-                            // Re-enable the endpoint, F429-like chips only
-                            if core_id == 0x0000_1200 || core_id == 0x0000_1100 {
-                                let ep = regs.endpoint_out(epnum as usize);
-                                modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
+                let rxflvl_enabled = read_reg!(otg_global, regs.global(), GINTMSK, RXFLVLM) != 0;
+                if rxflvl != 0 && rxflvl_enabled {
+                    // Drain the RX FIFO as far as possible, so that a SETUP queued behind OUT data
+                    // reaches EP0 in this call instead of waiting for the next interrupts
+                    let limit = if drain_fully { DRAIN_LIMIT } else { RX_ENTRIES_PER_POLL };
+                    for _ in 0..limit {
+                        match self.service_rx_entry(cs, core_id) {
+                            Ok(true) if read_reg!(otg_global, regs.global(), GINTSTS, RXFLVL) != 0 => {}
+                            Ok(_) => break,
+                            Err(()) => {
+                                self.set_poll_error(cs);
+                                return PollResult::None;
                             }
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-                        }
-                        _ => {
-                            read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
                         }
                     }
+                }
+
+                // OUT tokens for disabled endpoints are NAKed by the core, just account for them.
+                // The status bit is set regardless of DOEPMSK, so it's checked directly, once per
+                // poll however many tokens arrived.
+                for ep in self.allocator.endpoints_out.iter().flatten() {
+                    use crate::ral::endpoint_out;
+
+                    let ep_regs = regs.endpoint_out(ep.address().index());
+                    if read_reg!(endpoint_out, ep_regs, DOEPINT, OTEPDIS) != 0 {
+                        write_reg!(endpoint_out, ep_regs, DOEPINT, OTEPDIS: 1);
+
+                        let count = self.out_token_disabled_count.borrow(cs);
+                        count.set(count.get().wrapping_add(1));
+                        self.count_out_nak(cs, ep.address().index());
+                    }
+                }
 
-                    if status == 0x02 || status == 0x06 {
-                        if let Some(ep) = &self.allocator.endpoints_out[epnum as usize] {
-                            let mut buffer = ep.buffer.borrow(cs).borrow_mut();
-                            if buffer.state() == EndpointBufferState::Empty {
-                                read_reg!(otg_global, regs.global(), GRXSTSP); // pop GRXSTSP
-
-                                let is_setup = status == 0x06;
-                                buffer.fill_from_fifo(*regs, data_size as u16, is_setup).ok();
-
-                                // Re-enable the endpoint, F446-like chips only
-                                if core_id == 0x0000_2000 || core_id == 0x0000_2100 ||
-                                   core_id == 0x0000_2300 ||
-                                   core_id == 0x0000_3000 || core_id == 0x0000_3100 {
-                                    let ep = regs.endpoint_out(epnum as usize);
-                                    modify_reg!(endpoint_out, ep, DOEPCTL, CNAK: 1, EPENA: 1);
-                                }
-                            }
+                // More SETUP packets than DOEPTSIZ0.STUPCNT allows leave EP0 wedged
+                {
+                    use crate::ral::endpoint_out;
+
+                    let ep_regs = regs.endpoint_out(0);
+                    if read_reg!(endpoint_out, ep_regs, DOEPINT, B2BSTUP) != 0 {
+                        write_reg!(endpoint_out, ep_regs, DOEPINT, B2BSTUP: 1);
+                        if !self.recover_setup_overflow(cs) {
+                            self.set_poll_error(cs);
+                            return PollResult::None;
                         }
                     }
                 }
 
+                // OEPINT is cleared by acknowledging the DOEPINTx events
+                if oep != 0 {
+                    for ep in self.allocator.endpoints_out.iter().flatten() {
+                        ep_out |= self.service_out_endpoint(cs, ep);
+                    }
+                }
+
+                #[cfg(feature = "dedicated-interrupts")]
+                {
+                    ep_in_complete |= self.dedicated_in_complete.borrow(cs).replace(0);
+                }
+
+                // Endpoints waiting for TX FIFO space are flagged for a retry of their write
+                self.update_tx_refills(cs);
+
                 if iep != 0 {
-                    for ep in &self.allocator.endpoints_in {
-                        if let Some(ep) = ep {
-                            let ep_regs = regs.endpoint_in(ep.address().index());
-                            if read_reg!(endpoint_in, ep_regs, DIEPINT, XFRC) != 0 {
-                                write_reg!(endpoint_in, ep_regs, DIEPINT, XFRC: 1);
-                                ep_in_complete |= 1 << ep.address().index();
-                            }
+                    let rounds = if drain_fully { DRAIN_LIMIT } else { 1 };
+                    for _ in 0..rounds {
+                        for ep in self.allocator.endpoints_in.iter().flatten() {
+                            ep_in_complete |= self.service_in_endpoint(cs, ep);
+                        }
+                        if read_reg!(otg_global, regs.global(), GINTSTS, IEPINT) == 0 {
+                            break;
+                        }
+                    }
+
+                    // Every serviced source has been acknowledged, so an endpoint that is still
+                    // flagged in DAINT either has a source the driver doesn't clear, or a new event
+                    // that arrived after it was serviced. Only the trace can tell them apart.
+                    #[cfg(feature = "trace")]
+                    for ep in self.allocator.endpoints_in.iter().flatten() {
+                        let bit = 1 << ep.address().index();
+                        if read_reg!(otg_device, regs.device(), DAINT, IEPINT) & bit != 0 {
+                            self.trace(cs, TraceEvent::InInterruptPending(ep.address()));
                         }
                     }
                 }
 
+                // Packets that haven't been read since their completion was reported are reported
+                // again, and so are SETUP packets
                 for ep in &self.allocator.endpoints_out {
                     if let Some(ep) = ep {
                         match ep.buffer_state() {
@@ -720,19 +5069,245 @@ impl<USB: UsbPeripheral> usb_device::bus::UsbBus for UsbBus<USB> {
                             EndpointBufferState::DataSetup => {
                                 ep_setup |= 1 << ep.address().index();
                             },
-                            EndpointBufferState::Empty => {},
+                            EndpointBufferState::SetupPending | EndpointBufferState::Empty => {},
                         }
                     }
                 }
 
+                self.filter_setup(cs, &mut ep_out, &mut ep_in_complete, &mut ep_setup);
+
+                self.gate_clocks_if_idle(cs);
+
                 if (ep_in_complete | ep_out | ep_setup) != 0 {
                     PollResult::Data { ep_out, ep_in_complete, ep_setup }
                 } else {
                     PollResult::None
                 }
             }
-        })
+        });
+
+        interrupt::free(|cs| {
+            // Level-triggered sources only count if they went away, new events may have arrived
+            let (global, endpoints) = self.pending_interrupts(cs);
+            if !matches!(result, PollResult::None) || pending.0 & !global != 0 || pending.1 & !endpoints != 0 {
+                self.poll_handled.borrow(cs).set(true);
+            }
+        });
+
+        result
     }
 
     const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestPeripheral;
+
+    unsafe impl UsbPeripheral for TestPeripheral {
+        const REGISTERS: *const () = core::ptr::null();
+        const HIGH_SPEED: bool = false;
+        const FIFO_DEPTH_WORDS: usize = 320;
+        const ENDPOINT_COUNT: usize = 4;
+
+        fn enable() {}
+
+        fn ahb_frequency_hz(&self) -> u32 {
+            48_000_000
+        }
+    }
+
+    type TestBus = UsbBus<TestPeripheral>;
+
+    const IDENTITY_MAP: [u8; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn tx_fifo_sizes_control_only() {
+        // Only EP0 is allocated, the data endpoint FIFOs take no FIFO RAM
+        let sizes = tx_fifo_sizes_for(&[16, 0, 0, 0, 0, 0, 0, 0, 0], &IDENTITY_MAP, &[0; 9]);
+        assert_eq!(sizes, [16, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let rx_fifo_size = (64 / 4 + RX_FIFO_HEADROOM_WORDS) as u16;
+        assert!(TestBus::fifo_layout_fits(rx_fifo_size, &sizes));
+        assert_eq!(TestBus::fifo_layout_words(rx_fifo_size, &sizes), 16 + 30 + 16);
+    }
+
+    #[test]
+    fn tx_fifo_sizes_shared_and_overridden() {
+        // EP1 and EP3 share FIFO 1, which is sized for the larger one
+        let map = [0, 1, 2, 1, 4, 5, 6, 7, 8];
+        let sizes = tx_fifo_sizes_for(&[16, 16, 32, 128, 0, 0, 0, 0, 0], &map, &[0; 9]);
+        assert_eq!(sizes, [16, 128, 32, 0, 0, 0, 0, 0, 0]);
+
+        let sizes = tx_fifo_sizes_for(&[16, 16, 32, 128, 0, 0, 0, 0, 0], &map, &[0, 0, 64, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sizes, [16, 128, 64, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn fifo_layout_fits_depth() {
+        let sizes = [16, 64, 64, 0, 0, 0, 0, 0, 0];
+        assert!(TestBus::fifo_layout_fits(176, &sizes));
+        assert!(!TestBus::fifo_layout_fits(177, &sizes));
+
+        // FIFOs beyond the endpoint count aren't programmed and don't count
+        let sizes = [16, 64, 64, 0, 1000, 0, 0, 0, 0];
+        assert!(TestBus::fifo_layout_fits(176, &sizes));
+    }
+
+    #[test]
+    fn fifo_profile_control_only() {
+        let endpoints = [
+            ExpectedEndpoint {
+                address: EndpointAddress::from_parts(0, UsbDirection::Out),
+                ep_type: EndpointType::Control,
+                max_packet_size: 8,
+            },
+            ExpectedEndpoint {
+                address: EndpointAddress::from_parts(0, UsbDirection::In),
+                ep_type: EndpointType::Control,
+                max_packet_size: 8,
+            },
+        ];
+        let profile = FifoProfile::compute(&endpoints);
+        assert_eq!(profile.rx_fifo_size_words, 2 + RX_FIFO_HEADROOM_WORDS as u16);
+        assert_eq!(profile.tx_fifo_sizes_words, [16, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(TestBus::fifo_layout_fits(profile.rx_fifo_size_words, &profile.tx_fifo_sizes_words));
+    }
+
+    #[test]
+    fn fifo_profile_data_endpoints() {
+        let endpoint = |index, direction, ep_type, max_packet_size| ExpectedEndpoint {
+            address: EndpointAddress::from_parts(index, direction),
+            ep_type,
+            max_packet_size,
+        };
+        let endpoints = [
+            endpoint(0, UsbDirection::Out, EndpointType::Control, 64),
+            endpoint(0, UsbDirection::In, EndpointType::Control, 64),
+            endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64),
+            endpoint(1, UsbDirection::In, EndpointType::Bulk, 64),
+            endpoint(2, UsbDirection::In, EndpointType::Interrupt, 8),
+            endpoint(3, UsbDirection::In, EndpointType::Isochronous, 255),
+        ];
+        let profile = FifoProfile::compute(&endpoints);
+        assert_eq!(profile.rx_fifo_size_words, 16 + 16 + RX_FIFO_HEADROOM_WORDS as u16);
+        assert_eq!(profile.tx_fifo_sizes_words, [16, 16, 16, 64, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn status_stage_without_data() {
+        // SET_CONFIGURATION and SET_ADDRESS are answered with a ZLP from the device
+        for request_type in [0x00, 0x01, 0x21] {
+            let data_in = has_data_in(request_type);
+            assert!(is_status_stage(data_in, UsbDirection::In));
+            assert!(!is_status_stage(data_in, UsbDirection::Out));
+        }
+    }
+
+    #[test]
+    fn status_stage_after_data_in() {
+        // GET_DESCRIPTOR and class IN requests end with a ZLP from the host
+        for request_type in [0x80, 0x81, 0xa1] {
+            let data_in = has_data_in(request_type);
+            assert!(is_status_stage(data_in, UsbDirection::Out));
+            assert!(!is_status_stage(data_in, UsbDirection::In));
+        }
+    }
+
+    #[test]
+    fn decode_grxsts_fields() {
+        // SETUP data packet of 8 bytes on EP0, DATA0
+        let status = decode_grxsts((0x06 << 17) | (8 << 4));
+        assert_eq!(status, RxStatus {
+            epnum: 0,
+            byte_count: 8,
+            dpid: 0,
+            packet_status: PacketStatus::SetupData,
+            frame_number: 0,
+        });
+
+        // OUT data packet of 64 bytes on EP3, DATA1, frame 5
+        let status = decode_grxsts((5 << 21) | (0x02 << 17) | (2 << 15) | (64 << 4) | 3);
+        assert_eq!(status.epnum, 3);
+        assert_eq!(status.byte_count, 64);
+        assert_eq!(status.dpid, 2);
+        assert_eq!(status.packet_status, PacketStatus::OutData);
+        assert_eq!(status.frame_number, 5);
+    }
+
+    #[test]
+    fn decode_grxsts_packet_status() {
+        let expected = [
+            (0x01, PacketStatus::GlobalOutNak),
+            (0x02, PacketStatus::OutData),
+            (0x03, PacketStatus::OutComplete),
+            (0x04, PacketStatus::SetupComplete),
+            (0x06, PacketStatus::SetupData),
+            (0x00, PacketStatus::Reserved(0x00)),
+            (0x05, PacketStatus::Reserved(0x05)),
+            (0x0f, PacketStatus::Reserved(0x0f)),
+        ];
+        for (pktsts, packet_status) in expected {
+            assert_eq!(decode_grxsts(pktsts << 17).packet_status, packet_status);
+        }
+    }
+
+    #[test]
+    fn decode_grxsts_any_value() {
+        // Fields stay within their widths for arbitrary values, including the unused top bits
+        let mut raw: u32 = 0x1234_5678;
+        for _ in 0..100_000 {
+            raw = raw.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let status = decode_grxsts(raw);
+            assert!(status.epnum <= 0xf);
+            assert!(status.byte_count <= 0x7ff);
+            assert!(status.dpid <= 0x3);
+            assert!(status.frame_number <= 0xf);
+            if let PacketStatus::Reserved(pktsts) = status.packet_status {
+                assert!(pktsts <= 0xf);
+            }
+        }
+
+        let status = decode_grxsts(u32::MAX);
+        assert_eq!(status.epnum, 0xf);
+        assert_eq!(status.byte_count, 0x7ff);
+        assert_eq!(status.packet_status, PacketStatus::Reserved(0xf));
+    }
+
+    #[test]
+    fn idle_frames_full_speed_wrap() {
+        let mask = UsbSpeed::Full.frame_number_mask();
+        let mut tracker = IdleTracker { last_frame: 2046, idle_frames: 0 };
+        tracker.update(1, mask, false);
+        assert_eq!(tracker.idle_frames, 3);
+        tracker.update(1, mask, true);
+        assert_eq!(tracker.idle_frames, 0);
+    }
+
+    #[test]
+    fn idle_frames_high_speed_wrap() {
+        let mask = UsbSpeed::High.frame_number_mask();
+        let mut tracker = IdleTracker { last_frame: 0x3ffe, idle_frames: 0 };
+        tracker.update(1, mask, false);
+        assert_eq!(tracker.idle_frames, 3);
+    }
+
+    #[test]
+    fn config_snapshot_bytes_round_trip() {
+        let mut config = ConfigSnapshot::default();
+        config.endpoints_in[0] = EndpointSnapshot { max_packet_size: 64, ep_type: 0, allocated: 1 };
+        config.endpoints_out[2] = EndpointSnapshot { max_packet_size: 0x1ff, ep_type: 2, allocated: 1 };
+        config.rx_fifo_size_words = 0x123;
+        config.tx_fifo_size_words = [16, 32, 0, 0, 0, 0, 0, 0, 0x201];
+        config.tx_fifo_numbers = [0, 1, 1, 3, 4, 5, 6, 7, 8];
+
+        let bytes = config.to_bytes();
+        assert_eq!(&bytes[0..4], &[64, 0, 0, 1]);
+        assert_eq!(&bytes[9 * 4 + 2 * 4..9 * 4 + 3 * 4], &[0xff, 0x01, 2, 1]);
+        assert_eq!(&bytes[2 * 9 * 4..2 * 9 * 4 + 2], &[0x23, 0x01]);
+        assert_eq!(bytes[ConfigSnapshot::SIZE - 7], 1);
+        assert_eq!(ConfigSnapshot::from_bytes(&bytes), config);
+    }
+}
@@ -1,12 +1,13 @@
 use usb_device::{Result, UsbError, UsbDirection};
-use usb_device::endpoint::EndpointAddress;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use crate::endpoint_memory::{EndpointBuffer, EndpointBufferState};
-use crate::ral::{read_reg, write_reg, modify_reg, endpoint_in, endpoint_out, endpoint0_out};
-use crate::target::{fifo_write, UsbRegisters};
+use crate::ral::{read_reg, write_reg, modify_reg, otg_global, otg_device, endpoint_in, endpoint_out, endpoint0_out};
+use crate::target::{fifo_write, fifo_write_words, UsbRegisters};
 use crate::target::interrupt::{self, CriticalSection, Mutex};
 use core::ops::{Deref, DerefMut};
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use crate::transition::EndpointDescriptor;
+use crate::bus::{EndpointState, TransferStatus};
 use crate::UsbPeripheral;
 
 pub fn set_stalled(usb: UsbRegisters, address: EndpointAddress, stalled: bool) {
@@ -38,15 +39,64 @@ pub fn is_stalled(usb: UsbRegisters, address: EndpointAddress) -> bool {
     stall != 0
 }
 
+/// Number of register reads after which a wait for the core is given up
+const SPIN_LIMIT: u32 = 100_000;
+
+/// Spins until `done` returns `true`, returns `false` if the core doesn't get there in time
+pub fn spin_until(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..SPIN_LIMIT {
+        if done() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Requests the global OUT NAK and waits until it becomes effective, returns `false` if it
+/// doesn't in time
+///
+/// GINTSTS.GONAKEFF is set whether or not it's unmasked in GINTMSK, so it can be polled here
+/// without routing it to the interrupt.
+pub fn set_global_out_nak(usb: UsbRegisters) -> bool {
+    modify_reg!(otg_device, usb.device(), DCTL, SGONAK: 1);
+
+    //spin_until(|| read_reg!(otg_global, usb.global(), GINTSTS, GOUTNAKEFF) != 0)
+    // The bit is called BOUTNAKEFF on HS cores
+    spin_until(|| read_reg!(otg_global, usb.global(), GINTSTS) & (1 << 7) != 0)
+}
+
+/// Clears the global OUT NAK
+pub fn clear_global_out_nak(usb: UsbRegisters) {
+    modify_reg!(otg_device, usb.device(), DCTL, CGONAK: 1);
+}
+
+/// Requests the global IN NAK and waits until it becomes effective, returns `false` if it
+/// doesn't in time
+///
+/// Like GONAKEFF, GINTSTS.GINAKEFF is set even while it's masked.
+pub fn set_global_in_nak(usb: UsbRegisters) -> bool {
+    modify_reg!(otg_device, usb.device(), DCTL, SGINAK: 1);
+    spin_until(|| read_reg!(otg_global, usb.global(), GINTSTS, GINAKEFF) != 0)
+}
+
+/// Clears the global IN NAK
+pub fn clear_global_in_nak(usb: UsbRegisters) {
+    modify_reg!(otg_device, usb.device(), DCTL, CGINAK: 1);
+}
+
 /// Arbitrates access to the endpoint-specific registers and packet buffer memory.
 pub struct Endpoint {
     descriptor: EndpointDescriptor,
     usb: UsbRegisters,
+    active_max_packet_size: Mutex<Cell<u16>>,
+    configured: Mutex<Cell<bool>>,
 }
 
 impl Endpoint {
     pub fn new<USB: UsbPeripheral>(descriptor: EndpointDescriptor) -> Endpoint {
         Endpoint {
+            active_max_packet_size: Mutex::new(Cell::new(descriptor.max_packet_size)),
+            configured: Mutex::new(Cell::new(false)),
             descriptor,
             usb: UsbRegisters::new::<USB>()
         }
@@ -56,6 +106,55 @@ impl Endpoint {
         self.descriptor.address
     }
 
+    pub fn ep_type(&self) -> EndpointType {
+        self.descriptor.ep_type
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        self.descriptor.max_packet_size
+    }
+
+    /// Returns the maximum packet size of the current alternate setting
+    pub fn active_max_packet_size(&self, cs: &CriticalSection) -> u16 {
+        self.active_max_packet_size.borrow(cs).get()
+    }
+
+    /// Sets the maximum packet size of the current alternate setting, takes effect on the next
+    /// `configure` call
+    pub fn set_active_max_packet_size(&self, cs: &CriticalSection, max_packet_size: u16) {
+        self.active_max_packet_size.borrow(cs).set(max_packet_size);
+    }
+
+    /// Returns `true` between `configure` and `deconfigure`
+    pub fn is_configured(&self, cs: &CriticalSection) -> bool {
+        self.configured.borrow(cs).get()
+    }
+
+    /// Returns `true` if the DxEPCTL fields read back from the core match the configuration
+    fn matches_hardware(&self, cs: &CriticalSection, usbaep: u32, eptyp: u32, mpsiz: u32) -> bool {
+        let size = self.active_max_packet_size(cs);
+        if self.index() == 0 {
+            // EP0 is always active and of the control type, MPSIZ is encoded
+            let expected = match size {
+                8 => 0b11,
+                16 => 0b10,
+                32 => 0b01,
+                _ => 0b00,
+            };
+            eptyp == 0 && mpsiz & 0b11 == expected
+        } else {
+            usbaep == 1 && eptyp == self.descriptor.ep_type as u32 && mpsiz == size as u32
+        }
+    }
+
+    fn state_from(&self, cs: &CriticalSection, enabled: u32) -> EndpointState {
+        match (self.is_configured(cs), enabled) {
+            (false, _) => EndpointState::Allocated,
+            (true, 0) => EndpointState::Configured,
+            (true, _) => EndpointState::Active,
+        }
+    }
+
     #[inline(always)]
     fn index(&self) -> u8 {
         self.descriptor.address.index() as u8
@@ -74,19 +173,22 @@ impl EndpointIn {
         }
     }
 
-    pub fn configure(&self, _cs: &CriticalSection) {
+    pub fn configure(&self, cs: &CriticalSection, tx_fifo: u8) {
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
+            let mpsiz = match self.active_max_packet_size(cs) {
                 8 => 0b11,
                 16 => 0b10,
                 32 => 0b01,
                 64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
+                other => {
+                    fatal!("Unsupported EP0 size: {}", other);
+                    0b00
+                }
             };
 
             let regs = self.usb.endpoint_in(self.index() as usize);
             write_reg!(endpoint_in, regs, DIEPCTL, MPSIZ: mpsiz as u32, SNAK: 1);
-            write_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT: 0, XFRSIZ: self.descriptor.max_packet_size as u32);
+            write_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT: 0, XFRSIZ: self.active_max_packet_size(cs) as u32);
         } else {
             let regs = self.usb.endpoint_in(self.index() as usize);
             write_reg!(endpoint_in, regs, DIEPCTL,
@@ -94,13 +196,17 @@ impl EndpointIn {
                 USBAEP: 1,
                 EPTYP: self.descriptor.ep_type as u32,
                 SD0PID_SEVNFRM: 1,
-                TXFNUM: self.index() as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                TXFNUM: tx_fifo as u32,
+                MPSIZ: self.active_max_packet_size(cs) as u32
             );
         }
+
+        self.configured.borrow(cs).set(true);
     }
 
-    pub fn deconfigure(&self, _cs: &CriticalSection) {
+    pub fn deconfigure(&self, cs: &CriticalSection) {
+        self.configured.borrow(cs).set(false);
+
         let regs = self.usb.endpoint_in(self.index() as usize);
 
         // deactivating endpoint
@@ -119,24 +225,116 @@ impl EndpointIn {
         // TODO: deconfiguring TX FIFO
     }
 
+    /// Returns the state of the endpoint, `Active` while a transfer is pending
+    pub fn state(&self, cs: &CriticalSection) -> EndpointState {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        self.state_from(cs, read_reg!(endpoint_in, regs, DIEPCTL, EPENA))
+    }
+
+    /// Returns `true` if DIEPCTLx holds the configured type and maximum packet size
+    pub fn verify(&self, cs: &CriticalSection) -> bool {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        let (usbaep, eptyp, mpsiz) = read_reg!(endpoint_in, regs, DIEPCTL, USBAEP, EPTYP, MPSIZ);
+        self.matches_hardware(cs, usbaep, eptyp, mpsiz)
+    }
+
     pub fn write(&self, buf: &[u8]) -> Result<()> {
+        self.start_packet(buf.len())?;
+        fifo_write(self.usb, self.index(), buf);
+        Ok(())
+    }
+
+    /// Writes as much of `buf` as one EP0 transfer can hold, returns the number of bytes written
+    ///
+    /// DIEPTSIZ0 allows up to 3 packets and 127 bytes per transfer. Unless all of `buf` fits,
+    /// only full packets are written, so that the data stage isn't ended by a short packet.
+    pub fn write_control_data(&self, buf: &[u8]) -> Result<usize> {
+        let ep = self.usb.endpoint_in(0);
+        if read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let max_packet_size = interrupt::free(|cs| self.active_max_packet_size(cs)) as usize;
+        let limit = core::cmp::min(3 * max_packet_size, 127);
+        let len = if buf.len() <= limit { buf.len() } else { limit / max_packet_size * max_packet_size };
+        let packets = core::cmp::max(len.div_ceil(max_packet_size), 1);
+
+        if len.div_ceil(4) > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
+            return Err(UsbError::WouldBlock);
+        }
+
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: packets as u32, XFRSIZ: len as u32);
+        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+        fifo_write(self.usb, self.index(), &buf[..len]);
+
+        Ok(len)
+    }
+
+    /// Writes a packet of `len` bytes that has already been packed into FIFO words
+    pub fn write_words(&self, words: &[u32], len: usize) -> Result<()> {
+        self.start_packet(len)?;
+        fifo_write_words(self.usb, self.index(), words);
+        Ok(())
+    }
+
+    /// Checks that a packet of `len` bytes can be written and enables the endpoint for it, the
+    /// packet has to be pushed into the TX FIFO right after
+    fn start_packet(&self, len: usize) -> Result<()> {
         let ep = self.usb.endpoint_in(self.index() as usize);
         if self.index() != 0 && read_reg!(endpoint_in, ep, DIEPCTL, EPENA) != 0{
             return Err(UsbError::WouldBlock);
         }
 
-        if buf.len() > self.descriptor.max_packet_size as usize {
+        if len > interrupt::free(|cs| self.active_max_packet_size(cs)) as usize {
             return Err(UsbError::BufferOverflow);
         }
 
-        if !buf.is_empty() {
+        if len != 0 {
             // Check for FIFO free space
-            let size_words = (buf.len() + 3) / 4;
+            let size_words = (len + 3) / 4;
             if size_words > read_reg!(endpoint_in, ep, DTXFSTS, INEPTFSAV) as usize {
                 return Err(UsbError::WouldBlock);
             }
         }
 
+        if self.descriptor.ep_type == EndpointType::Isochronous {
+            // Schedule the packet for the next frame
+            let frame_number = read_reg!(otg_device, self.usb.device(), DSTS, FNSOF);
+            if frame_number & 1 == 0 {
+                //modify_reg!(endpoint_in, ep, DIEPCTL, SODDFRM: 1);
+                modify_reg!(endpoint_in, ep, DIEPCTL, |r| r | (1 << 29));
+            } else {
+                modify_reg!(endpoint_in, ep, DIEPCTL, SD0PID_SEVNFRM: 1);
+            }
+        }
+
+        #[cfg(feature = "fs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: len as u32);
+        #[cfg(feature = "hs")]
+        write_reg!(endpoint_in, ep, DIEPTSIZ, MCNT: 1, PKTCNT: 1, XFRSIZ: len as u32);
+
+        modify_reg!(endpoint_in, ep, DIEPCTL, CNAK: 1, EPENA: 1);
+
+        Ok(())
+    }
+
+    /// Writes a packet of up to 8 bytes, skipping the FIFO space check.
+    ///
+    /// When the endpoint is disabled, its previous packet has been sent and its dedicated TX FIFO
+    /// is empty, so a small packet always fits. Falls back to `write` if the packet is larger,
+    /// the endpoint is isochronous, or the endpoint shares its TX FIFO with other endpoints.
+    pub fn write_small(&self, buf: &[u8]) -> Result<()> {
+        let ep = self.usb.endpoint_in(self.index() as usize);
+        let (enabled, tx_fifo) = read_reg!(endpoint_in, ep, DIEPCTL, EPENA, TXFNUM);
+        if enabled != 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        if buf.len() > 8 || buf.len() > interrupt::free(|cs| self.active_max_packet_size(cs)) as usize ||
+           self.descriptor.ep_type == EndpointType::Isochronous || tx_fifo != self.index() as u32 {
+            return self.write(buf);
+        }
+
         #[cfg(feature = "fs")]
         write_reg!(endpoint_in, ep, DIEPTSIZ, PKTCNT: 1, XFRSIZ: buf.len() as u32);
         #[cfg(feature = "hs")]
@@ -148,33 +346,126 @@ impl EndpointIn {
 
         Ok(())
     }
+
+    /// Aborts an isochronous IN transfer that couldn't complete in its frame.
+    ///
+    /// The endpoint is disabled and its TX FIFO is flushed, so that the next packet can be
+    /// written for the upcoming frame. `frame_number` is the current frame number (DSTS.FNSOF).
+    /// Returns `false` if the core didn't respond in time.
+    pub fn abort_incomplete_iso(&self, _cs: &CriticalSection, frame_number: u32) -> bool {
+        if self.descriptor.ep_type != EndpointType::Isochronous {
+            return true;
+        }
+
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        let (enabled, odd_frame) = read_reg!(endpoint_in, regs, DIEPCTL, EPENA, EONUM_DPID);
+
+        // Only the endpoint scheduled for the frame that has just ended is affected, a packet
+        // written for the next frame must not be discarded
+        if enabled == 0 || odd_frame != (frame_number & 1) {
+            return true;
+        }
+
+        modify_reg!(endpoint_in, regs, DIEPCTL, SNAK: 1, EPDIS: 1);
+        if !spin_until(|| read_reg!(endpoint_in, regs, DIEPINT, EPDISD) != 0) {
+            return false;
+        }
+        write_reg!(endpoint_in, regs, DIEPINT, EPDISD: 1);
+
+        let fifo = read_reg!(endpoint_in, regs, DIEPCTL, TXFNUM);
+        modify_reg!(otg_global, self.usb.global(), GRSTCTL, TXFNUM: fifo, TXFFLSH: 1);
+        spin_until(|| read_reg!(otg_global, self.usb.global(), GRSTCTL, TXFFLSH) == 0)
+    }
+
+    /// Returns the number of bytes of the pending IN transfer that the host hasn't acknowledged
+    ///
+    /// DIEPTSIZx.XFRSIZ only counts the bytes that haven't been written to the TX FIFO yet. The
+    /// core keeps a packet in the FIFO until the host has acknowledged it, so the occupied part
+    /// of the FIFO (`fifo_depth_words` minus DTXFSTSx.INEPTFSAV) is added, in whole words.
+    pub fn pending_bytes(&self, fifo_depth_words: u16) -> u16 {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        if read_reg!(endpoint_in, regs, DIEPCTL, EPENA) == 0 {
+            return 0;
+        }
+        let unwritten = read_reg!(endpoint_in, regs, DIEPTSIZ, XFRSIZ) as u16;
+        let available = read_reg!(endpoint_in, regs, DTXFSTS, INEPTFSAV) as u16;
+        unwritten.saturating_add(fifo_depth_words.saturating_sub(available).saturating_mul(4))
+    }
+
+    /// Returns the part of the current transfer that hasn't been sent yet (DIEPTSIZx)
+    pub fn transfer_status(&self) -> TransferStatus {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        let (packets, bytes) = read_reg!(endpoint_in, regs, DIEPTSIZ, PKTCNT, XFRSIZ);
+        TransferStatus { packets: packets as u16, bytes }
+    }
+
+    /// Aborts a pending IN transfer.
+    ///
+    /// The endpoint is NAKed and disabled, then its TX FIFO is flushed. Afterwards the endpoint
+    /// is idle and accepts the next `write`. Does nothing if no transfer is pending.
+    ///
+    /// Returns `false` if the core doesn't finish one of the steps in time, the endpoint may
+    /// still be enabled then.
+    pub fn abort(&self, _cs: &CriticalSection) -> bool {
+        let regs = self.usb.endpoint_in(self.index() as usize);
+        if read_reg!(endpoint_in, regs, DIEPCTL, EPENA) == 0 {
+            return true;
+        }
+
+        modify_reg!(endpoint_in, regs, DIEPCTL, SNAK: 1);
+        if !spin_until(|| read_reg!(endpoint_in, regs, DIEPINT, INEPNE) != 0) {
+            return false;
+        }
+
+        modify_reg!(endpoint_in, regs, DIEPCTL, EPDIS: 1);
+        if !spin_until(|| read_reg!(endpoint_in, regs, DIEPINT, EPDISD) != 0) {
+            return false;
+        }
+        write_reg!(endpoint_in, regs, DIEPINT, EPDISD: 1, INEPNE: 1);
+
+        let fifo = read_reg!(endpoint_in, regs, DIEPCTL, TXFNUM);
+        modify_reg!(otg_global, self.usb.global(), GRSTCTL, TXFNUM: fifo, TXFFLSH: 1);
+        if !spin_until(|| read_reg!(otg_global, self.usb.global(), GRSTCTL, TXFFLSH) == 0) {
+            return false;
+        }
+
+        // Drop a completion that may have raced with the abort
+        write_reg!(endpoint_in, regs, DIEPINT, XFRC: 1);
+        true
+    }
 }
 
 pub struct EndpointOut {
     common: Endpoint,
     pub(crate) buffer: Mutex<RefCell<EndpointBuffer>>,
+    /// Second receive buffer, filled while `buffer` still holds an unread packet
+    spare: Mutex<RefCell<Option<EndpointBuffer>>>,
 }
 
 impl EndpointOut {
-    pub fn new<USB: UsbPeripheral>(descriptor: EndpointDescriptor, buffer: EndpointBuffer) -> EndpointOut {
+    pub fn new<USB: UsbPeripheral>(descriptor: EndpointDescriptor, buffer: EndpointBuffer, spare: Option<EndpointBuffer>) -> EndpointOut {
         EndpointOut {
             common: Endpoint::new::<USB>(descriptor),
             buffer: Mutex::new(RefCell::new(buffer)),
+            spare: Mutex::new(RefCell::new(spare)),
         }
     }
 
-    pub fn configure(&self, _cs: &CriticalSection) {
+    pub fn configure(&self, cs: &CriticalSection) {
         if self.index() == 0 {
-            let mpsiz = match self.descriptor.max_packet_size {
+            let mpsiz = match self.active_max_packet_size(cs) {
                 8 => 0b11,
                 16 => 0b10,
                 32 => 0b01,
                 64 => 0b00,
-                other => panic!("Unsupported EP0 size: {}", other),
+                other => {
+                    fatal!("Unsupported EP0 size: {}", other);
+                    0b00
+                }
             };
 
             let regs = self.usb.endpoint0_out();
-            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.descriptor.max_packet_size as u32);
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 1, PKTCNT: 1, XFRSIZ: self.active_max_packet_size(cs) as u32);
             modify_reg!(endpoint0_out, regs, DOEPCTL0, MPSIZ: mpsiz as u32, EPENA: 1, CNAK: 1);
         } else {
             let regs = self.usb.endpoint_out(self.index() as usize);
@@ -184,29 +475,186 @@ impl EndpointOut {
                 EPENA: 1,
                 USBAEP: 1,
                 EPTYP: self.descriptor.ep_type as u32,
-                MPSIZ: self.descriptor.max_packet_size as u32
+                MPSIZ: self.active_max_packet_size(cs) as u32
             );
         }
+
+        self.configured.borrow(cs).set(true);
     }
 
-    pub fn deconfigure(&self, _cs: &CriticalSection) {
+    /// Deactivates and disables the endpoint.
+    ///
+    /// Returns `false` if the global OUT NAK or the disable didn't take effect in time.
+    pub fn deconfigure(&self, cs: &CriticalSection) -> bool {
+        self.configured.borrow(cs).set(false);
+
         let regs = self.usb.endpoint_out(self.index() as usize);
 
         // deactivating endpoint
         modify_reg!(endpoint_out, regs, DOEPCTL, USBAEP: 0);
 
-        // disabling endpoint
+        // disabling endpoint, OUT endpoints can only be disabled while the global OUT NAK is
+        // effective, and it has to stay effective until the core confirms the disable
+        let mut disabled = true;
         if read_reg!(endpoint_out, regs, DOEPCTL, EPENA) != 0 && self.index() != 0 {
-            modify_reg!(endpoint_out, regs, DOEPCTL, EPDIS: 1)
+            disabled = set_global_out_nak(self.usb);
+            if disabled {
+                modify_reg!(endpoint_out, regs, DOEPCTL, SNAK: 1, EPDIS: 1);
+                disabled = spin_until(|| read_reg!(endpoint_out, regs, DOEPINT, EPDISD) != 0);
+            }
+            clear_global_out_nak(self.usb);
         }
 
         // clean EP interrupts
         write_reg!(endpoint_out, regs, DOEPINT, 0xff);
+        disabled
+    }
+
+    /// Returns the state of the endpoint, `Active` while it is armed for reception
+    pub fn state(&self, cs: &CriticalSection) -> EndpointState {
+        let enabled = if self.index() == 0 {
+            read_reg!(endpoint0_out, self.usb.endpoint0_out(), DOEPCTL0, EPENA)
+        } else {
+            read_reg!(endpoint_out, self.usb.endpoint_out(self.index() as usize), DOEPCTL, EPENA)
+        };
+        self.state_from(cs, enabled)
+    }
+
+    /// Returns `true` if DOEPCTLx holds the configured type and maximum packet size
+    pub fn verify(&self, cs: &CriticalSection) -> bool {
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        let (usbaep, eptyp, mpsiz) = read_reg!(endpoint_out, regs, DOEPCTL, USBAEP, EPTYP, MPSIZ);
+        self.matches_hardware(cs, usbaep, eptyp, mpsiz)
+    }
+
+    /// Re-arms an isochronous OUT endpoint that didn't receive its packet in the previous frame.
+    ///
+    /// `frame_number` is the current frame number (DSTS.FNSOF).
+    pub fn rearm_incomplete_iso(&self, _cs: &CriticalSection, frame_number: u32) {
+        if self.descriptor.ep_type != EndpointType::Isochronous || self.index() == 0 {
+            return;
+        }
+
+        let regs = self.usb.endpoint_out(self.index() as usize);
+        let (enabled, odd_frame) = read_reg!(endpoint_out, regs, DOEPCTL, EPENA, EONUM_DPID);
+
+        // Only the endpoint armed for the frame that has just ended is affected
+        if enabled == 0 || odd_frame != (frame_number & 1) {
+            return;
+        }
+
+        if frame_number & 1 == 0 {
+            modify_reg!(endpoint_out, regs, DOEPCTL, SODDFRM: 1, CNAK: 1, EPENA: 1);
+        } else {
+            modify_reg!(endpoint_out, regs, DOEPCTL, SD0PID_SEVNFRM: 1, CNAK: 1, EPENA: 1);
+        }
+    }
+
+    /// Arms the endpoint for the reception of the next packet.
+    ///
+    /// For endpoints other than EP0 the data toggle is reset to DATA0, as required after a
+    /// halt condition has been cleared.
+    pub fn rearm(&self, cs: &CriticalSection) {
+        if self.index() == 0 {
+            let regs = self.usb.endpoint0_out();
+            // Allow up to 3 back-to-back SETUP packets
+            write_reg!(endpoint0_out, regs, DOEPTSIZ0, STUPCNT: 3, PKTCNT: 1, XFRSIZ: self.active_max_packet_size(cs) as u32);
+            modify_reg!(endpoint0_out, regs, DOEPCTL0, CNAK: 1, EPENA: 1);
+        } else {
+            let regs = self.usb.endpoint_out(self.index() as usize);
+            write_reg!(endpoint_out, regs, DOEPTSIZ, PKTCNT: 1, XFRSIZ: self.active_max_packet_size(cs) as u32);
+            modify_reg!(endpoint_out, regs, DOEPCTL, SD0PID_SEVNFRM: 1, CNAK: 1, EPENA: 1);
+        }
+    }
+
+    /// Returns the part of the current transfer that hasn't been received yet (DOEPTSIZx)
+    pub fn transfer_status(&self) -> TransferStatus {
+        let (packets, bytes) = if self.index() == 0 {
+            read_reg!(endpoint0_out, self.usb.endpoint0_out(), DOEPTSIZ0, PKTCNT, XFRSIZ)
+        } else {
+            read_reg!(endpoint_out, self.usb.endpoint_out(self.index() as usize), DOEPTSIZ, PKTCNT, XFRSIZ)
+        };
+        TransferStatus { packets: packets as u16, bytes }
+    }
+
+    /// Returns `true` if there is a free buffer for the packet at the head of the RX FIFO
+    pub fn can_accept(&self, cs: &CriticalSection, is_setup: bool) -> bool {
+        if self.buffer.borrow(cs).borrow().accepts(is_setup) {
+            return true;
+        }
+
+        !is_setup && self.spare.borrow(cs).borrow().as_ref().is_some_and(|spare| spare.accepts(false))
+    }
+
+    /// Copies the packet at the head of the RX FIFO into the first free buffer
+    pub fn fill_from_fifo(&self, cs: &CriticalSection, usb: UsbRegisters, data_size: u16, is_setup: bool) {
+        let mut buffer = self.buffer.borrow(cs).borrow_mut();
+        if buffer.accepts(is_setup) {
+            buffer.fill_from_fifo(usb, data_size, is_setup).ok();
+        } else if let Some(spare) = self.spare.borrow(cs).borrow_mut().as_mut() {
+            spare.fill_from_fifo(usb, data_size, is_setup).ok();
+        }
+    }
+
+    /// Returns bmRequestType of the SETUP packet that has just been received, if any
+    pub fn setup_request_type(&self, cs: &CriticalSection) -> Option<u8> {
+        self.buffer.borrow(cs).borrow().setup_request_type()
+    }
+
+    pub fn setup_packet(&self, cs: &CriticalSection) -> Option<[u8; 8]> {
+        self.buffer.borrow(cs).borrow().setup_packet()
+    }
+
+    pub fn clear_buffers(&self, cs: &CriticalSection) {
+        self.buffer.borrow(cs).borrow_mut().clear();
+        if let Some(spare) = self.spare.borrow(cs).borrow_mut().as_mut() {
+            spare.clear();
+        }
+    }
+
+    /// Moves the packet that arrived in the spare buffer in the meantime to the front
+    fn promote_spare(&self, cs: &CriticalSection, buffer: &mut EndpointBuffer) {
+        if buffer.state() == EndpointBufferState::Empty {
+            if let Some(spare) = self.spare.borrow(cs).borrow_mut().as_mut() {
+                if spare.state() != EndpointBufferState::Empty {
+                    core::mem::swap(buffer, spare);
+                }
+            }
+        }
+    }
+
+    /// Reads the received packet, see `EndpointBuffer::read_packet` for `partial`
+    pub fn read(&self, buf: &mut [u8], partial: bool) -> Result<usize> {
+        interrupt::free(|cs| {
+            let mut buffer = self.buffer.borrow(cs).borrow_mut();
+            let result = buffer.read_packet(buf, partial);
+            self.promote_spare(cs, &mut buffer);
+            result
+        })
+    }
+
+    /// Borrows the unread part of the received packet in place, see `UsbBus::borrow_packet`
+    pub fn borrow_packet(&self, cs: &CriticalSection) -> Option<(*const u8, usize)> {
+        self.buffer.borrow(cs).borrow_mut().borrow_packet()
+    }
+
+    /// Releases the packet borrowed by `borrow_packet`
+    pub fn release_packet(&self, cs: &CriticalSection) {
+        let mut buffer = self.buffer.borrow(cs).borrow_mut();
+        if buffer.is_borrowed() {
+            buffer.release();
+            self.promote_spare(cs, &mut buffer);
+        } else if let Some(spare) = self.spare.borrow(cs).borrow_mut().as_mut() {
+            // A bus reset may have moved the borrowed buffer out of the way
+            if spare.is_borrowed() {
+                spare.release();
+            }
+        }
     }
 
-    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+    pub fn remaining(&self) -> usize {
         interrupt::free(|cs| {
-            self.buffer.borrow(cs).borrow_mut().read_packet(buf)
+            self.buffer.borrow(cs).borrow().remaining()
         })
     }
 
@@ -11,13 +11,19 @@ pub enum EndpointBufferState {
     Empty,
     DataOut,
     DataSetup,
+    /// SETUP packet received, but the SETUP stage hasn't completed yet
+    SetupPending,
 }
 
 pub struct EndpointBuffer {
     buffer: &'static mut [VolatileCell<u32>],
     data_size: u16,
+    read_offset: u16,
     has_data: bool,
     is_setup: bool,
+    setup_complete: bool,
+    /// The packet is borrowed in place and must not be overwritten
+    borrowed: bool,
 }
 
 impl EndpointBuffer {
@@ -25,47 +31,114 @@ impl EndpointBuffer {
         Self {
             buffer: unsafe { &mut *(buffer as *mut [u32] as *mut [VolatileCell<u32>]) },
             data_size: 0,
+            read_offset: 0,
             has_data: false,
-            is_setup: false
+            is_setup: false,
+            setup_complete: false,
+            borrowed: false,
         }
     }
 
-    pub fn read_packet(&mut self, mut buf: &mut [u8]) -> Result<usize> {
-        if !self.has_data {
-            return Err(UsbError::WouldBlock)
+    /// Reads the unread part of the received packet into `buf`.
+    ///
+    /// Returns `UsbError::BufferOverflow` and keeps the packet if `buf` is too short, unless
+    /// `partial` is set. Then only `buf.len()` bytes are read and the rest can be read by the
+    /// following calls, and reading into an empty buffer discards the rest of the packet and
+    /// returns `UsbError::BufferOverflow`. The buffer is released for the next packet once the
+    /// whole packet has been read.
+    pub fn read_packet(&mut self, buf: &mut [u8], partial: bool) -> Result<usize> {
+        if self.borrowed {
+            return Err(UsbError::WouldBlock);
+        }
+
+        match self.state() {
+            EndpointBufferState::Empty | EndpointBufferState::SetupPending => {
+                return Err(UsbError::WouldBlock)
+            }
+            EndpointBufferState::DataOut | EndpointBufferState::DataSetup => {}
         }
 
-        let data_size = self.data_size as usize;
+        let offset = self.read_offset as usize;
+        let remaining = self.data_size as usize - offset;
 
-        if buf.len() < data_size {
+        if !partial && buf.len() < remaining {
+            return Err(UsbError::BufferOverflow);
+        }
+        if buf.is_empty() && remaining > 0 {
+            self.has_data = false;
             return Err(UsbError::BufferOverflow);
         }
 
-        let mut index = 0;
-        let mut current_size = data_size;
-        while current_size >= 4 {
-            let word = self.buffer[index].get();
-            index += 1;
+        let count = core::cmp::min(buf.len(), remaining);
 
-            let bytes = word.to_ne_bytes();
-            buf[..4].copy_from_slice(&bytes);
-            buf = &mut buf[4..];
+        let mut copied = 0;
+        while copied < count {
+            let position = offset + copied;
+            let bytes = self.buffer[position / 4].get().to_ne_bytes();
+            let start = position % 4;
+            let n = core::cmp::min(4 - start, count - copied);
+            buf[copied..copied + n].copy_from_slice(&bytes[start..start + n]);
+            copied += n;
+        }
 
-            current_size -= 4;
+        self.read_offset += count as u16;
+        if self.read_offset == self.data_size {
+            self.has_data = false;
         }
-        if current_size > 0 {
-            let word = self.buffer[index].get();
-            let bytes = word.to_ne_bytes();
-            buf[..current_size].copy_from_slice(&bytes[..current_size]);
+
+        Ok(count)
+    }
+
+    /// Returns the number of bytes of the received packet that haven't been read yet
+    pub fn remaining(&self) -> usize {
+        if self.has_data {
+            (self.data_size - self.read_offset) as usize
+        } else {
+            0
+        }
+    }
+
+    /// Borrows the unread part of a received OUT packet in place
+    ///
+    /// Returns a pointer to the data and its length. The buffer isn't refilled until `release`
+    /// is called.
+    pub fn borrow_packet(&mut self) -> Option<(*const u8, usize)> {
+        if self.borrowed || self.state() != EndpointBufferState::DataOut {
+            return None;
         }
 
+        self.borrowed = true;
+        let offset = self.read_offset as usize;
+        let data = unsafe { (self.buffer.as_ptr() as *const u8).add(offset) };
+        Some((data, self.data_size as usize - offset))
+    }
+
+    pub fn is_borrowed(&self) -> bool {
+        self.borrowed
+    }
+
+    /// Ends a `borrow_packet`, releasing the buffer for the next packet
+    pub fn release(&mut self) {
+        self.borrowed = false;
         self.has_data = false;
+    }
+
+    /// Returns `true` if the next packet can be stored in the buffer
+    pub fn accepts(&self, is_setup: bool) -> bool {
+        let state = self.state();
+        !self.borrowed && (state == EndpointBufferState::Empty ||
+            (is_setup && state == EndpointBufferState::SetupPending))
+    }
 
-        Ok(data_size)
+    /// Discards the received packet, if any
+    pub fn clear(&mut self) {
+        self.has_data = false;
+        self.setup_complete = false;
     }
 
     pub fn fill_from_fifo(&mut self, usb: UsbRegisters, data_size: u16, is_setup: bool) -> Result<()> {
-        if self.has_data {
+        // A SETUP packet that hasn't completed its stage yet may be replaced by a newer one
+        if self.borrowed || (self.has_data && self.state() != EndpointBufferState::SetupPending) {
             return Err(UsbError::WouldBlock);
         }
 
@@ -77,16 +150,50 @@ impl EndpointBuffer {
         fifo_read_into(usb, &self.buffer[..words]);
 
         self.is_setup = is_setup;
+        self.setup_complete = false;
         self.data_size = data_size;
+        self.read_offset = 0;
         self.has_data = true;
 
         Ok(())
     }
 
+    /// Returns bmRequestType of the SETUP packet held by the buffer, if any
+    pub fn setup_request_type(&self) -> Option<u8> {
+        if self.has_data && self.is_setup && self.data_size > 0 {
+            Some(self.buffer[0].get().to_ne_bytes()[0])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the SETUP packet in the buffer without consuming it
+    pub fn setup_packet(&self) -> Option<[u8; 8]> {
+        if self.has_data && self.is_setup && self.data_size >= 8 {
+            let mut packet = [0; 8];
+            packet[..4].copy_from_slice(&self.buffer[0].get().to_ne_bytes());
+            packet[4..].copy_from_slice(&self.buffer[1].get().to_ne_bytes());
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the SETUP stage as completed, making the received SETUP packet available for reading
+    pub fn complete_setup(&mut self) {
+        if self.has_data && self.is_setup {
+            self.setup_complete = true;
+        }
+    }
+
     pub fn state(&self) -> EndpointBufferState {
         if self.has_data {
             if self.is_setup {
-                EndpointBufferState::DataSetup
+                if self.setup_complete {
+                    EndpointBufferState::DataSetup
+                } else {
+                    EndpointBufferState::SetupPending
+                }
             } else {
                 EndpointBufferState::DataOut
             }
@@ -107,11 +214,26 @@ impl Default for EndpointBuffer {
 }
 
 
+/// Extra RX FIFO space on top of the OUT endpoint buffers, see `UsbBus::rx_fifo_size_words`
+pub(crate) const RX_FIFO_HEADROOM_WORDS: usize = 30;
+
+/// Minimum TX FIFO size, reserved for EP0 before any other endpoint is allocated
+const EP0_TX_FIFO_MIN_WORDS: usize = 16;
+
+/// Allocation state saved by `EndpointMemoryAllocator::mark`
+#[derive(Clone, Copy)]
+pub struct AllocationMark {
+    next_free_offset: usize,
+    max_size_words: usize,
+    tx_fifo_size_words: [u16; 9],
+}
+
 pub struct EndpointMemoryAllocator<USB> {
     next_free_offset: usize,
     max_size_words: usize,
     memory: &'static mut [u32],
     tx_fifo_size_words: [u16; 9],
+    overflow: Option<(usize, usize)>,
     _marker: PhantomData<USB>,
 }
 
@@ -122,6 +244,7 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
             max_size_words: 0,
             memory,
             tx_fifo_size_words: [0; 9],
+            overflow: None,
             _marker: PhantomData
         }
     }
@@ -131,6 +254,14 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
 
         let offset = self.next_free_offset;
         if offset + size_words > self.memory.len() {
+            self.overflow = Some((size_words, self.memory.len() - offset));
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        // The RX FIFO grows with the buffers, it must not take the space reserved for EP0
+        let used = self.fifo_words_used();
+        if used + size_words > USB::FIFO_DEPTH_WORDS {
+            self.overflow = Some((size_words, USB::FIFO_DEPTH_WORDS.saturating_sub(used)));
             return Err(UsbError::EndpointMemoryOverflow);
         }
 
@@ -146,20 +277,23 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
 
     pub fn allocate_tx_buffer(&mut self, ep_number: u8, size: usize) -> Result<()> {
         let ep_number = ep_number as usize;
-        assert!(ep_number < self.tx_fifo_size_words.len());
+        if ep_number >= self.tx_fifo_size_words.len() {
+            return Err(UsbError::InvalidEndpoint);
+        }
 
         if self.tx_fifo_size_words[ep_number] != 0 {
             return Err(UsbError::InvalidEndpoint)
         }
 
-        let mut used = self.total_rx_buffer_size_words() as usize + 30;
-        for sz in &self.tx_fifo_size_words {
-            used += core::cmp::max(*sz as usize, 16);
+        let mut used = self.fifo_words_used();
+        if ep_number == 0 {
+            // EP0 takes the place of its reservation
+            used -= EP0_TX_FIFO_MIN_WORDS;
         }
-        used -= 16;
 
         let size_words = core::cmp::max((size + 3) / 4, 16);
         if (used + size_words) > USB::FIFO_DEPTH_WORDS {
+            self.overflow = Some((size_words, USB::FIFO_DEPTH_WORDS.saturating_sub(used)));
             return Err(UsbError::EndpointMemoryOverflow);
         }
 
@@ -168,11 +302,57 @@ impl<USB: UsbPeripheral> EndpointMemoryAllocator<USB> {
         Ok(())
     }
 
+    /// Saves the allocation state, so that a group of allocations can be undone with `restore`
+    pub fn mark(&self) -> AllocationMark {
+        AllocationMark {
+            next_free_offset: self.next_free_offset,
+            max_size_words: self.max_size_words,
+            tx_fifo_size_words: self.tx_fifo_size_words,
+        }
+    }
+
+    /// Frees everything allocated since `mark` was taken
+    ///
+    /// The buffers handed out since then must have been dropped, their memory is reused.
+    pub fn restore(&mut self, mark: AllocationMark) {
+        self.next_free_offset = mark.next_free_offset;
+        self.max_size_words = mark.max_size_words;
+        self.tx_fifo_size_words = mark.tx_fifo_size_words;
+    }
+
+    /// Returns the requested and available words of the last failed allocation
+    pub fn take_overflow(&mut self) -> Option<(usize, usize)> {
+        self.overflow.take()
+    }
+
+    /// Returns the number of FIFO words taken by the allocated endpoints
+    ///
+    /// The RX FIFO headroom and the minimum EP0 TX FIFO are always reserved, so that data
+    /// endpoints can't take the space required for control transfers.
+    fn fifo_words_used(&self) -> usize {
+        let mut used = self.total_rx_buffer_size_words() as usize + RX_FIFO_HEADROOM_WORDS;
+        used += core::cmp::max(self.tx_fifo_size_words[0] as usize, EP0_TX_FIFO_MIN_WORDS);
+        for sz in &self.tx_fifo_size_words[1..] {
+            used += *sz as usize;
+        }
+        used
+    }
+
     /// Returns the size of memory allocated for OUT endpoints in words
     pub fn total_rx_buffer_size_words(&self) -> u16 {
         self.next_free_offset as u16
     }
 
+    /// Returns the number of words of endpoint memory used by OUT endpoint buffers
+    pub fn used_rx_words(&self) -> usize {
+        self.next_free_offset
+    }
+
+    /// Returns the total number of words of endpoint memory
+    pub fn capacity_words(&self) -> usize {
+        self.memory.len()
+    }
+
     pub fn tx_fifo_size_words(&self, ep_number: usize) -> u16 {
         self.tx_fifo_size_words[ep_number]
     }
@@ -8,6 +8,20 @@ compile_error!("choose only one USB mode");
 #[cfg(not(any(feature = "fs", feature ="hs")))]
 compile_error!("select USB mode feature (fs/hs)");
 
+#[cfg(all(feature = "dedicated-interrupts", not(feature = "hs")))]
+compile_error!("dedicated endpoint interrupts are only available on HS cores");
+
+/// Panics with the given message, unless the `panic-free` feature is enabled.
+///
+/// The code following the invocation provides the fallback behavior for `panic-free` builds.
+macro_rules! fatal {
+    ($($arg:tt)*) => {
+        if cfg!(not(feature = "panic-free")) {
+            panic!($($arg)*);
+        }
+    };
+}
+
 mod endpoint;
 mod endpoint_memory;
 
@@ -18,6 +32,26 @@ pub mod bus;
 
 pub use crate::bus::UsbBus;
 
+/// Sharing the USB device between an interrupt handler and the main context.
+pub mod shared;
+
+pub use crate::shared::SharedUsbBus;
+
+/// Blocking helpers for bring-up and tests.
+pub mod blocking;
+
+/// Per-endpoint throughput instrumentation.
+#[cfg(feature = "throughput")]
+pub mod throughput;
+
+/// Register-level event trace for post-mortem debugging.
+#[cfg(feature = "trace")]
+pub mod trace;
+
+/// Minimal CDC-ACM serial port for bring-up.
+#[cfg(feature = "serial")]
+pub mod serial;
+
 mod ral;
 mod transition;
 
@@ -29,6 +63,8 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     const REGISTERS: *const ();
 
     /// true for High Speed variants of the peripheral, false for Full Speed
+    ///
+    /// This is checked against the core hardware configuration when the bus is enabled.
     const HIGH_SPEED: bool;
 
     /// FIFO size in 32-bit words
@@ -46,10 +82,69 @@ pub unsafe trait UsbPeripheral: Send + Sync {
     /// Returns PHY type that should be used for USB peripheral
     fn phy_type(&self) -> PhyType { PhyType::InternalFullSpeed }
 
+    /// Returns the clock source of the high-speed PHY
+    ///
+    /// This selects the clock the core expects from a UTMI+ or ULPI PHY (GUSBCFG.PHYLPCS) and
+    /// is programmed before the PHY is enabled. It is ignored for `PhyType::InternalFullSpeed`,
+    /// which always runs from the 48 MHz USB clock of the chip. The PLL of the internal
+    /// high-speed PHY has to be set up for the chosen source in `setup_internal_hs_phy`.
+    fn phy_clock_source(&self) -> PhyClockSource { PhyClockSource::Pll480MHz }
+
+    /// Returns the FS timeout calibration (GUSBCFG.TOCAL), 0 to 7
+    ///
+    /// This is the number of PHY clocks added to the interpacket timeout of the core, and can
+    /// compensate for the delay of long traces or cables at full speed. Returns 1 with the `hs`
+    /// feature and 0 otherwise by default, matching earlier releases.
+    fn timeout_calibration(&self) -> u8 { if cfg!(feature = "hs") { 1 } else { 0 } }
+
+    /// Returns the USB turnaround time (GUSBCFG.TRDT) to use at full speed, 0 to 15
+    ///
+    /// By default (`None`) the turnaround time is derived from `ahb_frequency_hz` as given in
+    /// the reference manuals. A larger value gives a marginal internal FS PHY more time between
+    /// packets, at the cost of throughput. The value isn't used at high speed.
+    fn full_speed_turnaround_time(&self) -> Option<u8> { None }
+
     /// Performs initial setup of the internal high-speed PHY
     ///
     /// This function should turn on LDO and PLL and wait for PHY clock to become stable.
     fn setup_internal_hs_phy(&self) {}
+
+    /// Returns `true` if the MCU has been reset while the USB device may have been enumerated
+    ///
+    /// Implement this by checking the reset flags of the MCU, e.g. for a watchdog reset. The
+    /// device is then kept disconnected by `UsbBus::enable` until `UsbBus::finish_warm_reset`
+    /// is called, so that the host enumerates it again. Returns `false` by default.
+    fn warm_reset(&self) -> bool { false }
+
+    /// Called while the driver waits for the core during initialization, returns `false` to
+    /// give up
+    ///
+    /// The core is polled for AHB idle, the end of the core soft-reset and FIFO flushes in rounds
+    /// of a fixed number of register reads. This is called after each unfinished round with the
+    /// number of rounds so far, and may yield or delay on a slowly clocked core. Giving up is a
+    /// fatal error in `UsbBus::enable`. Gives up after 10 rounds by default.
+    fn init_wait_backoff(&self, round: u32) -> bool { round < 10 }
+
+    /// Returns `true` if the OUT endpoint `ep_addr` should get a second receive buffer
+    ///
+    /// With two buffers the driver can accept the next packet from the RX FIFO while the
+    /// previous one hasn't been read yet. The second packet is only reported by the next `poll`,
+    /// so classes should keep reading until `WouldBlock`. Called once per OUT endpoint during
+    /// allocation, never for EP0. Returns `false` by default.
+    fn double_buffered_out(&self, _ep_addr: usb_device::endpoint::EndpointAddress) -> bool { false }
+
+    /// Called when an endpoint allocation or the FIFO layout doesn't fit into the FIFO RAM
+    ///
+    /// This is a diagnostic hook for logging what overflowed during development. It is only
+    /// called on the failure path, before the error is returned or the driver panics, and does
+    /// nothing by default.
+    fn fifo_overflow(&self, _report: bus::FifoOverflow) {}
+
+    /// Called by `UsbBus::enable` when the core's ID registers don't match this peripheral
+    ///
+    /// The driver keeps initializing the core, since some vendors report their own values. This
+    /// is a diagnostic hook and does nothing by default.
+    fn core_mismatch(&self, _mismatch: bus::CoreMismatch) {}
 }
 
 /// USB PHY type
@@ -66,3 +161,16 @@ pub enum PhyType {
     /// External ULPI High-Speed PHY
     ExternalHighSpeed,
 }
+
+/// Clock source of the high-speed PHY
+///
+/// `Pll480MHz` is the only source supported by the internal high-speed PHY of the STM32F7 and
+/// STM32H7 chips. External ULPI PHYs support either source, depending on how their reference
+/// clock is wired.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PhyClockSource {
+    /// 480 MHz clock from the PHY PLL, the UTMI interface runs at 30 or 60 MHz
+    Pll480MHz,
+    /// 48 MHz external clock, high speed is not available in this mode
+    External48MHz,
+}
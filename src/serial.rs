@@ -0,0 +1,232 @@
+//! Minimal CDC-ACM serial port for bring-up and debugging
+
+use usb_device::class_prelude::*;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usb_device::Result;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+const PACKET_SIZE: usize = 64;
+
+struct CdcAcmClass<'a, B: UsbBus> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, B>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    line_coding: [u8; 7],
+    dtr: bool,
+}
+
+impl<B: UsbBus> CdcAcmClass<'_, B> {
+    fn new(alloc: &UsbBusAllocator<B>) -> CdcAcmClass<'_, B> {
+        CdcAcmClass {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(PACKET_SIZE as u16),
+            write_ep: alloc.bulk(PACKET_SIZE as u16),
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: [0x00, 0xc2, 0x01, 0x00, 0, 0, 8],
+            dtr: false,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(self.comm_if, 2, USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE)?;
+
+        writer.interface(self.comm_if, USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE)?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_ACM, 0x02])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_UNION, self.comm_if.into(), self.data_if.into()])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, self.data_if.into()])?;
+        writer.endpoint(&self.comm_ep)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.dtr = false;
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.request_type != control::RequestType::Class
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_SET_LINE_CODING if xfer.data().len() >= 7 => {
+                self.line_coding.copy_from_slice(&xfer.data()[..7]);
+                xfer.accept().ok();
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.dtr = req.value & 0x0001 != 0;
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if req.request_type != control::RequestType::Class
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING => {
+                let line_coding = self.line_coding;
+                xfer.accept_with(&line_coding).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+/// USB serial port implementing the `embedded-hal` serial traits.
+///
+/// The serial port owns the `usb-device` stack with a single CDC-ACM function and polls it from
+/// `read`, `write` and `flush`, so it works without a USB interrupt handler as long as it is
+/// accessed often enough. It is meant for getting a console running on a new board, use a
+/// proper CDC-ACM class implementation for anything else.
+pub struct SerialPort<'a, B: UsbBus> {
+    device: UsbDevice<'a, B>,
+    class: CdcAcmClass<'a, B>,
+    rx_buf: [u8; PACKET_SIZE],
+    rx_pos: usize,
+    rx_len: usize,
+    tx_buf: [u8; PACKET_SIZE],
+    tx_len: usize,
+}
+
+impl<B: UsbBus> SerialPort<'_, B> {
+    /// Creates a serial port, allocating the CDC-ACM endpoints from `alloc`.
+    pub fn new(alloc: &UsbBusAllocator<B>, vid_pid: UsbVidPid) -> SerialPort<'_, B> {
+        let class = CdcAcmClass::new(alloc);
+        let device = UsbDeviceBuilder::new(alloc, vid_pid)
+            .composite_with_iads()
+            .build();
+
+        SerialPort {
+            device,
+            class,
+            rx_buf: [0; PACKET_SIZE],
+            rx_pos: 0,
+            rx_len: 0,
+            tx_buf: [0; PACKET_SIZE],
+            tx_len: 0,
+        }
+    }
+
+    /// Polls the USB stack, this has to be called regularly while the port isn't being used.
+    pub fn poll(&mut self) -> bool {
+        self.device.poll(&mut [&mut self.class])
+    }
+
+    /// Returns `true` if the device has been configured and the host has asserted DTR.
+    pub fn is_open(&self) -> bool {
+        self.device.state() == UsbDeviceState::Configured && self.class.dtr
+    }
+
+    fn flush_packet(&mut self) -> nb::Result<(), UsbError> {
+        if self.tx_len == 0 {
+            return Ok(());
+        }
+
+        if self.device.state() != UsbDeviceState::Configured {
+            // Nobody is listening, drop the data
+            self.tx_len = 0;
+            return Ok(());
+        }
+
+        match self.class.write_ep.write(&self.tx_buf[..self.tx_len]) {
+            Ok(_) => {
+                self.tx_len = 0;
+                Ok(())
+            }
+            Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(e) => Err(nb::Error::Other(e)),
+        }
+    }
+}
+
+impl<B: UsbBus> embedded_hal::serial::Read<u8> for SerialPort<'_, B> {
+    type Error = UsbError;
+
+    fn read(&mut self) -> nb::Result<u8, UsbError> {
+        self.poll();
+
+        if self.rx_pos == self.rx_len {
+            match self.class.read_ep.read(&mut self.rx_buf) {
+                Ok(count) => {
+                    self.rx_pos = 0;
+                    self.rx_len = count;
+                }
+                Err(UsbError::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(e) => return Err(nb::Error::Other(e)),
+            }
+        }
+
+        if self.rx_pos == self.rx_len {
+            // Zero-length packet
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let byte = self.rx_buf[self.rx_pos];
+        self.rx_pos += 1;
+        Ok(byte)
+    }
+}
+
+impl<B: UsbBus> embedded_hal::serial::Write<u8> for SerialPort<'_, B> {
+    type Error = UsbError;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), UsbError> {
+        self.poll();
+
+        if self.tx_len == PACKET_SIZE {
+            self.flush_packet()?;
+        }
+
+        self.tx_buf[self.tx_len] = word;
+        self.tx_len += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), UsbError> {
+        self.poll();
+        self.flush_packet()
+    }
+}
@@ -0,0 +1,84 @@
+//! USB device shared between an interrupt handler and the main context
+
+use core::cell::RefCell;
+use usb_device::bus::UsbBus;
+use usb_device::class::UsbClass;
+use usb_device::device::UsbDevice;
+use crate::target::interrupt::{self, Mutex};
+
+struct Shared<'a, B: UsbBus, C> {
+    device: UsbDevice<'a, B>,
+    class: C,
+}
+
+/// A `usb-device` stack and its class that can be used from an interrupt handler and from the
+/// main context.
+///
+/// Every access runs in a critical section, so the interrupt handler can't preempt the main
+/// context while it holds the device. Nested accesses, e.g. calling `with_class` from within
+/// `with_class`, return `None` instead of panicking with a double borrow.
+///
+/// The interrupt handler should only call `poll`, the main context uses `with_class` and
+/// `with_device` to read from and write to the class. Several classes can be combined into one
+/// type implementing `UsbClass`.
+pub struct SharedUsbBus<'a, B: UsbBus, C: UsbClass<B>> {
+    inner: Mutex<RefCell<Option<Shared<'a, B, C>>>>,
+}
+
+impl<'a, B: UsbBus, C: UsbClass<B>> SharedUsbBus<'a, B, C> {
+    /// Creates an empty shared bus, suitable for a `static`.
+    pub const fn new() -> Self {
+        SharedUsbBus {
+            inner: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Moves the device and its class into the shared bus.
+    ///
+    /// **Panics:** if the shared bus is already initialized. With the `panic-free` feature the
+    /// previous device and class are replaced instead.
+    pub fn init(&self, device: UsbDevice<'a, B>, class: C) {
+        interrupt::free(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            if inner.is_some() {
+                fatal!("SharedUsbBus is already initialized");
+            }
+            *inner = Some(Shared { device, class });
+        });
+    }
+
+    /// Polls the device, to be called from the USB interrupt handler.
+    ///
+    /// Returns `true` if the class may have new data available, like `UsbDevice::poll`. Returns
+    /// `false` if the shared bus is not initialized yet.
+    pub fn poll(&self) -> bool {
+        self.with(|shared| shared.device.poll(&mut [&mut shared.class])).unwrap_or(false)
+    }
+
+    /// Runs `f` with exclusive access to the class, to be called from the main context.
+    ///
+    /// Returns `None` if the shared bus is not initialized or is already being accessed.
+    pub fn with_class<R>(&self, f: impl FnOnce(&mut C) -> R) -> Option<R> {
+        self.with(|shared| f(&mut shared.class))
+    }
+
+    /// Runs `f` with exclusive access to the device and the class.
+    ///
+    /// Returns `None` if the shared bus is not initialized or is already being accessed.
+    pub fn with_device<R>(&self, f: impl FnOnce(&mut UsbDevice<'a, B>, &mut C) -> R) -> Option<R> {
+        self.with(|shared| f(&mut shared.device, &mut shared.class))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Shared<'a, B, C>) -> R) -> Option<R> {
+        interrupt::free(|cs| {
+            let mut inner = self.inner.borrow(cs).try_borrow_mut().ok()?;
+            inner.as_mut().map(f)
+        })
+    }
+}
+
+impl<B: UsbBus, C: UsbClass<B>> Default for SharedUsbBus<'_, B, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -9,7 +9,7 @@ pub use riscv::interrupt;
 
 use crate::ral::{otg_global, otg_device, otg_pwrclk, otg_global_dieptxfx, endpoint_in, endpoint0_out, endpoint_out};
 use crate::UsbPeripheral;
-use crate::ral::register::RWRegister;
+use crate::ral::register::{RORegister, RWRegister};
 
 pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8]) {
     let fifo = usb.fifo(channel.into());
@@ -27,6 +27,14 @@ pub fn fifo_write(usb: UsbRegisters, channel: impl Into<usize>, mut buf: &[u8])
     }
 }
 
+pub fn fifo_write_words(usb: UsbRegisters, channel: impl Into<usize>, words: &[u32]) {
+    let fifo = usb.fifo(channel.into());
+
+    for &word in words {
+        fifo.write(word);
+    }
+}
+
 pub fn fifo_read_into(usb: UsbRegisters, buf: &[VolatileCell<u32>]) {
     let fifo = usb.fifo(0);
 
@@ -63,11 +71,34 @@ impl UsbRegisters {
 
     #[inline(always)]
     pub fn fifo(&self, channel: usize) -> &'static RWRegister<u32> {
-        assert!(channel <= 15);
+        if channel > 15 {
+            fatal!("FIFO channel {} is out of range", channel);
+        }
+        // Don't point outside of the register block, even if the channel is out of range
+        let channel = core::cmp::min(channel, 15);
         let address = self.0 + 0x1000 + channel * 0x1000;
         unsafe { &*(address as *const RWRegister<u32>) }
     }
 
+    /// Synopsys ID register (GSNPSID), not described in the vendor register definitions
+    #[inline(always)]
+    pub fn gsnpsid(&self) -> &'static RORegister<u32> {
+        unsafe { &*((self.0 + 0x40) as *const RORegister<u32>) }
+    }
+
+    /// User hardware configuration register 2 (GHWCFG2), not described in the vendor register definitions
+    #[inline(always)]
+    pub fn ghwcfg2(&self) -> &'static RORegister<u32> {
+        unsafe { &*((self.0 + 0x48) as *const RORegister<u32>) }
+    }
+
+    /// LPM configuration register (GLPMCFG), not described in the vendor register definitions and
+    /// only present on cores with LPM support
+    #[inline(always)]
+    pub fn glpmcfg(&self) -> &'static RORegister<u32> {
+        unsafe { &*((self.0 + 0x54) as *const RORegister<u32>) }
+    }
+
     #[inline(always)]
     pub fn dieptxfx(&self, index: usize) -> &'static otg_global_dieptxfx::RegisterBlock {
         let address = self.0 + 0x100 + 4 * index;
@@ -0,0 +1,87 @@
+//! Per-endpoint throughput instrumentation, enabled by the `throughput` feature.
+
+use usb_device::UsbDirection;
+use usb_device::endpoint::EndpointAddress;
+
+/// Throughput measured on an endpoint.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThroughputStats {
+    /// Number of bytes transferred.
+    pub bytes: u32,
+
+    /// Number of (micro)frames elapsed between the first and the last transfer.
+    pub frames: u32,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Counter {
+    stats: ThroughputStats,
+    last_frame: Option<u16>,
+}
+
+impl Counter {
+    fn record(&mut self, frame_number: u16, frame_mask: u16, bytes: usize) {
+        if let Some(last_frame) = self.last_frame {
+            let elapsed = frame_number.wrapping_sub(last_frame) & frame_mask;
+            self.stats.frames = self.stats.frames.wrapping_add(elapsed as u32);
+        }
+        self.last_frame = Some(frame_number);
+        self.stats.bytes = self.stats.bytes.wrapping_add(bytes as u32);
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub(crate) struct ThroughputCounters {
+    counters_in: [Counter; 9],
+    counters_out: [Counter; 9],
+}
+
+impl ThroughputCounters {
+    fn counter(&mut self, ep_addr: EndpointAddress) -> Option<&mut Counter> {
+        match ep_addr.direction() {
+            UsbDirection::In => self.counters_in.get_mut(ep_addr.index()),
+            UsbDirection::Out => self.counters_out.get_mut(ep_addr.index()),
+        }
+    }
+
+    /// Records a transfer of `bytes` in (micro)frame `frame_number`. `frame_mask` selects the
+    /// DSTS.FNSOF bits that count at the enumerated speed.
+    pub fn record(&mut self, ep_addr: EndpointAddress, frame_number: u32, frame_mask: u16, bytes: usize) {
+        if let Some(counter) = self.counter(ep_addr) {
+            counter.record(frame_number as u16 & frame_mask, frame_mask, bytes);
+        }
+    }
+
+    pub fn stats(&mut self, ep_addr: EndpointAddress) -> Option<ThroughputStats> {
+        self.counter(ep_addr).map(|counter| counter.stats)
+    }
+
+    pub fn reset(&mut self, ep_addr: EndpointAddress) {
+        if let Some(counter) = self.counter(ep_addr) {
+            *counter = Counter::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_speed_frame_wrap() {
+        let ep_addr = EndpointAddress::from_parts(1, UsbDirection::In);
+        let mut counters = ThroughputCounters::default();
+        counters.record(ep_addr, 2046, 0x7ff, 64);
+        counters.record(ep_addr, 1, 0x7ff, 64);
+        assert_eq!(counters.stats(ep_addr), Some(ThroughputStats { bytes: 128, frames: 3 }));
+    }
+
+    #[test]
+    fn high_speed_frame_wrap() {
+        let ep_addr = EndpointAddress::from_parts(1, UsbDirection::Out);
+        let mut counters = ThroughputCounters::default();
+        counters.record(ep_addr, 0x3ffe, 0x3fff, 512);
+        counters.record(ep_addr, 1, 0x3fff, 512);
+        assert_eq!(counters.stats(ep_addr), Some(ThroughputStats { bytes: 1024, frames: 3 }));
+    }
+}
@@ -0,0 +1,108 @@
+//! Register-level event trace for post-mortem debugging, enabled by the `trace` feature.
+//!
+//! The number of records kept defaults to 64 and can be changed by setting the
+//! `SYNOPSYS_USB_OTG_TRACE_LEN` environment variable at build time.
+
+use usb_device::endpoint::EndpointAddress;
+use crate::bus::{PacketStatus, UsbSpeed};
+
+/// Number of records kept in the trace buffer
+pub const TRACE_LEN: usize = parse_len(option_env!("SYNOPSYS_USB_OTG_TRACE_LEN"), 64);
+
+const fn parse_len(value: Option<&str>, default: usize) -> usize {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+
+    let mut len = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        assert!(digit.is_ascii_digit(), "SYNOPSYS_USB_OTG_TRACE_LEN must be a decimal number");
+        len = len * 10 + (digit - b'0') as usize;
+        i += 1;
+    }
+    assert!(len > 0, "SYNOPSYS_USB_OTG_TRACE_LEN must not be zero");
+    len
+}
+
+/// Event recorded in the trace buffer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// USB reset detected (GINTSTS.USBRST).
+    Reset,
+    /// Enumeration done (GINTSTS.ENUMDNE) at the given speed.
+    EnumerationDone(UsbSpeed),
+    /// Suspend detected (GINTSTS.USBSUSP).
+    Suspend,
+    /// Resume or remote wakeup detected (GINTSTS.WKUPINT).
+    Wakeup,
+    /// Entry popped from the RX FIFO.
+    RxStatus {
+        /// Endpoint number.
+        endpoint: u8,
+        /// Packet status.
+        status: PacketStatus,
+        /// Byte count.
+        byte_count: u16,
+    },
+    /// IN transfer completed (DIEPINTx.XFRC).
+    InComplete(EndpointAddress),
+    /// An IN endpoint is still flagged in DAINT after `poll` serviced it, either because of a
+    /// source the driver doesn't acknowledge or because of a new event.
+    InInterruptPending(EndpointAddress),
+    /// The STALL condition of an endpoint has been set or cleared.
+    Stall(EndpointAddress, bool),
+    /// TX FIFO flushed, 0x10 stands for all TX FIFOs.
+    TxFifoFlush(u8),
+    /// RX FIFO flushed.
+    RxFifoFlush,
+    /// A register poll timed out, see `UsbBus::take_poll_error`.
+    PollError,
+}
+
+/// Trace buffer entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// Frame number of the last received SOF (DSTS.FNSOF) when the event was recorded.
+    pub frame_number: u16,
+    /// The recorded event.
+    pub event: TraceEvent,
+}
+
+pub(crate) struct TraceBuffer {
+    records: [Option<TraceRecord>; TRACE_LEN],
+    next: usize,
+}
+
+impl TraceBuffer {
+    pub const fn new() -> Self {
+        Self {
+            records: [None; TRACE_LEN],
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, frame_number: u16, event: TraceEvent) {
+        self.records[self.next] = Some(TraceRecord { frame_number, event });
+        self.next = (self.next + 1) % TRACE_LEN;
+    }
+
+    /// Copies the newest records that fit into `out`, oldest first, and returns their number
+    pub fn copy_to(&self, out: &mut [TraceRecord]) -> usize {
+        let (newer, older) = self.records.split_at(self.next);
+        let records = older.iter().chain(newer.iter()).flatten();
+        let skip = records.clone().count().saturating_sub(out.len());
+        let mut count = 0;
+        for (slot, record) in out.iter_mut().zip(records.skip(skip)) {
+            *slot = *record;
+            count += 1;
+        }
+        count
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}